@@ -1,27 +1,173 @@
 use async_trait::async_trait;
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, Stream};
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{pin::Pin, sync::Arc, time::Duration};
 use thiserror::Error;
 
 /// Message history
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
+    #[serde(default)]
     pub content: String,
+    /// Tool calls requested by an assistant message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Call answered by a `tool` message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new<R: Into<String>, C: Into<String>>(role: R, content: C) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// Assistant turn that only requests tool calls
+    pub fn tool_calls(calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls: calls,
+            ..Self::new("assistant", "")
+        }
+    }
+
+    /// Result of a tool call
+    pub fn tool_result<C: Into<String>>(call_id: &str, content: C) -> Self {
+        Self {
+            tool_call_id: Some(call_id.to_string()),
+            ..Self::new("tool", content)
+        }
+    }
+}
+
+/// Function the model may call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: serde_json::Value,
+}
+
+/// Function call requested by the model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
+}
+
+/// Whether a response cache may answer the request
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CachePolicy {
+    #[default]
+    Use,
+    /// Always call the model, and don't store the answer
+    Bypass,
+}
+
+/// Sampling parameters, unset ones use the provider default
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+impl ModelParams {
+    /// Parameters set here, the rest taken from `base`
+    pub fn or(self, base: ModelParams) -> ModelParams {
+        ModelParams {
+            temperature: self.temperature.or(base.temperature),
+            top_p: self.top_p.or(base.top_p),
+            max_tokens: self.max_tokens.or(base.max_tokens),
+            presence_penalty: self.presence_penalty.or(base.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(base.frequency_penalty),
+        }
+    }
 }
 
 /// Request payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageRequest {
     pub messages: Vec<Message>,
+    pub tools: Vec<ToolDefinition>,
+    #[serde(default)]
+    pub params: ModelParams,
+    #[serde(skip)]
+    pub cache: CachePolicy,
+}
+
+impl MessageRequest {
+    pub fn new(messages: Vec<Message>) -> Self {
+        Self {
+            messages,
+            tools: Vec::new(),
+            params: ModelParams::default(),
+            cache: CachePolicy::default(),
+        }
+    }
+}
+
+/// Token counts reported by the provider
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
 }
 
 /// Completion response
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageResponse {
     pub text: String,
-    pub used_tokens: Option<usize>,
+    pub usage: Option<Usage>,
+    /// Model that actually answered, if reported
+    pub model: Option<String>,
+    /// Non-empty if the model wants tool results before answering
+    pub tool_calls: Vec<ToolCall>,
+    /// Answered from a response cache, nothing was spent on it
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+/// Streamed completion event
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// Next piece of generated text
+    Delta(String),
+    /// Sent once after the last delta
+    Done {
+        usage: Option<Usage>,
+        model: Option<String>,
+        /// see [`MessageResponse::cached`]
+        cached: bool,
+    },
 }
 
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<StreamEvent, ChatModelError>> + Send>>;
+
 /// ChatModel errors
 #[derive(Error, Debug)]
 pub enum ChatModelError {
@@ -31,10 +177,135 @@ pub enum ChatModelError {
     Json(#[from] serde_json::Error),
     #[error("API error: {0}")]
     Api(String),
+    #[error("Rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Authentication error: {0}")]
+    Auth(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Server error ({status}): {message}")]
+    Server { status: u16, message: String },
+    #[error("Request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("No recorded response for request {0}")]
+    CassetteMiss(String),
+}
+
+impl ChatModelError {
+    /// Classify a non-success HTTP response.
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: String) -> Self {
+        match status.as_u16() {
+            401 | 403 => Self::Auth(body),
+            429 => Self::RateLimited {
+                message: body,
+                retry_after: parse_retry_after(headers),
+            },
+            // the server gave up waiting for the request, sending it again is safe
+            408 => Self::Server {
+                status: 408,
+                message: body,
+            },
+            400..=499 => Self::BadRequest(body),
+            code => Self::Server {
+                status: code,
+                message: body,
+            },
+        }
+    }
+
+    /// Returns true if the same request may succeed when sent again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::Server { .. } | Self::Timeout(_) => true,
+            Self::Http(err) => err.is_timeout() || err.is_connect() || err.is_request(),
+            _ => false,
+        }
+    }
+
+    /// Delay requested by the server, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Read `retry-after-ms` or `Retry-After` (in seconds or as an HTTP date).
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+
+    let value = header("retry-after")?.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(secs.max(0.0)));
+    }
+
+    // e.g. "Wed, 21 Oct 2015 07:28:00 GMT", a date in the past means now
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
 }
 
 /// Completion API traits
 #[async_trait]
 pub trait ChatModel {
     async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError>;
+
+    /// Provider and model, used in logs and cache keys
+    fn label(&self) -> String;
+
+    /// Stream the completion as text deltas.
+    ///
+    /// Providers without streaming support yield the whole text as a single delta.
+    async fn generate_stream(&self, req: &MessageRequest) -> Result<ChatStream, ChatModelError> {
+        let resp = self.generate(req).await?;
+        Ok(Box::pin(stream::iter([
+            Ok(StreamEvent::Delta(resp.text)),
+            Ok(StreamEvent::Done {
+                usage: resp.usage,
+                model: resp.model,
+                cached: resp.cached,
+            }),
+        ])))
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel + Send + Sync + ?Sized> ChatModel for Box<M> {
+    async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
+        (**self).generate(req).await
+    }
+
+    fn label(&self) -> String {
+        (**self).label()
+    }
+
+    async fn generate_stream(&self, req: &MessageRequest) -> Result<ChatStream, ChatModelError> {
+        (**self).generate_stream(req).await
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel + Send + Sync + ?Sized> ChatModel for Arc<M> {
+    async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
+        (**self).generate(req).await
+    }
+
+    fn label(&self) -> String {
+        (**self).label()
+    }
+
+    async fn generate_stream(&self, req: &MessageRequest) -> Result<ChatStream, ChatModelError> {
+        (**self).generate_stream(req).await
+    }
 }
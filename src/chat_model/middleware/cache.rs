@@ -0,0 +1,241 @@
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use log::{debug, warn};
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    chat_model::core::{
+        CachePolicy, ChatModel, ChatModelError, ChatStream, MessageRequest, MessageResponse,
+        StreamEvent,
+    },
+    config::{CacheConfig, CONFIG},
+    metrics::{Metrics, METRICS},
+    persist::{read_json, write_json},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedResponse {
+    response: MessageResponse,
+    /// unix seconds
+    stored_at: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// In-memory LRU with TTL, optionally backed by a directory of JSON files
+pub struct ResponseCache {
+    ttl: Duration,
+    dir: Option<PathBuf>,
+    memory: Mutex<LruCache<String, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        let cap = NonZeroUsize::new(config.max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            ttl: Duration::from_secs(config.ttl),
+            dir: config.path.as_ref().map(PathBuf::from),
+            memory: Mutex::new(LruCache::new(cap)),
+        }
+    }
+
+    /// Hash of the model label and everything sent to it
+    pub fn key(label: &str, req: &MessageRequest) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(label.as_bytes());
+        hasher.update(serde_json::to_vec(req).unwrap_or_default());
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn is_fresh(&self, entry: &CachedResponse) -> bool {
+        now_secs().saturating_sub(entry.stored_at) < self.ttl.as_secs()
+    }
+
+    fn disk_path(&self, key: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", key)))
+    }
+
+    pub fn get(&self, key: &str) -> Option<MessageResponse> {
+        let mut memory = self.memory.lock().unwrap();
+        if let Some(entry) = memory.get(key) {
+            if self.is_fresh(entry) {
+                return Some(entry.response.clone());
+            }
+            memory.pop(key);
+        }
+
+        let path = self.disk_path(key).filter(|p| p.exists())?;
+        let entry: Option<CachedResponse> = read_json(&path.to_string_lossy());
+        match entry {
+            Some(entry) if self.is_fresh(&entry) => {
+                let response = entry.response.clone();
+                memory.put(key.to_string(), entry);
+                Some(response)
+            }
+            _ => {
+                let _ = fs::remove_file(path);
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, response: &MessageResponse) {
+        let entry = CachedResponse {
+            response: response.clone(),
+            stored_at: now_secs(),
+        };
+
+        if let Some(path) = self.disk_path(key) {
+            if let Err(err) = write_json(&path.to_string_lossy(), &entry) {
+                warn!("Failed to write cache entry {}: {}", path.display(), err);
+            }
+        }
+        self.memory.lock().unwrap().put(key.to_string(), entry);
+    }
+
+    /// Remove expired files from the on-disk store.
+    pub fn prune_disk(&self) {
+        let Some(dir) = &self.dir else { return };
+        let Ok(files) = fs::read_dir(dir) else { return };
+
+        for path in files.flatten().map(|f| f.path()) {
+            if !is_json(&path) {
+                continue;
+            }
+            let entry: Option<CachedResponse> = read_json(&path.to_string_lossy());
+            if !entry.is_some_and(|e| self.is_fresh(&e)) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    /// Drop the entry, in memory and on disk.
+    pub fn remove(&self, key: &str) {
+        self.memory.lock().unwrap().pop(key);
+        if let Some(path) = self.disk_path(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Cache shared by every account, see `Cache` in the config
+pub static RESPONSE_CACHE: Lazy<Arc<ResponseCache>> = Lazy::new(|| {
+    let cache = ResponseCache::new(&CONFIG.get().cache);
+    cache.prune_disk();
+    Arc::new(cache)
+});
+
+/// ChatModel decorator answering identical requests from a [`ResponseCache`]
+pub struct Cached<M> {
+    inner: M,
+    cache: Arc<ResponseCache>,
+}
+
+impl<M: ChatModel + Send + Sync> Cached<M> {
+    pub fn new(inner: M, cache: Arc<ResponseCache>) -> Self {
+        Self { inner, cache }
+    }
+
+    /// Key of the request, None if it must not be cached
+    fn lookup_key(&self, req: &MessageRequest) -> Option<String> {
+        (req.cache == CachePolicy::Use).then(|| ResponseCache::key(&self.inner.label(), req))
+    }
+
+    fn hit(&self, key: &str) -> Option<MessageResponse> {
+        match self.cache.get(key) {
+            Some(mut resp) => {
+                Metrics::incr(&METRICS.cache_hits);
+                debug!("Response cache hit {}", key);
+                resp.cached = true;
+                Some(resp)
+            }
+            None => {
+                Metrics::incr(&METRICS.cache_misses);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel + Send + Sync> ChatModel for Cached<M> {
+    async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
+        let Some(key) = self.lookup_key(req) else {
+            return self.inner.generate(req).await;
+        };
+        if let Some(resp) = self.hit(&key) {
+            return Ok(resp);
+        }
+
+        let resp = self.inner.generate(req).await?;
+        self.cache.put(&key, &resp);
+        Ok(resp)
+    }
+
+    fn label(&self) -> String {
+        self.inner.label()
+    }
+
+    /// Streams are cached once they complete; a stream dropped early is not stored.
+    async fn generate_stream(&self, req: &MessageRequest) -> Result<ChatStream, ChatModelError> {
+        let Some(key) = self.lookup_key(req) else {
+            return self.inner.generate_stream(req).await;
+        };
+        if let Some(resp) = self.hit(&key) {
+            return Ok(Box::pin(stream::iter([
+                Ok(StreamEvent::Delta(resp.text)),
+                Ok(StreamEvent::Done {
+                    usage: resp.usage,
+                    model: resp.model,
+                    cached: true,
+                }),
+            ])));
+        }
+
+        let cache = self.cache.clone();
+        let mut text = String::new();
+        let inner = self.inner.generate_stream(req).await?;
+        Ok(Box::pin(inner.map(move |event| {
+            match &event {
+                Ok(StreamEvent::Delta(delta)) => text.push_str(delta),
+                Ok(StreamEvent::Done { usage, model, .. }) => cache.put(
+                    &key,
+                    &MessageResponse {
+                        text: std::mem::take(&mut text),
+                        usage: *usage,
+                        model: model.clone(),
+                        tool_calls: Vec::new(),
+                        cached: false,
+                    },
+                ),
+                Err(_) => {}
+            }
+            event
+        })))
+    }
+}
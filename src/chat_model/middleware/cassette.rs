@@ -0,0 +1,106 @@
+use async_trait::async_trait;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{
+    chat_model::core::{ChatModel, ChatModelError, MessageRequest, MessageResponse},
+    persist::{read_json, write_json},
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum CassetteMode {
+    /// Call the wrapped model and save every exchange
+    RECORD,
+    /// Answer from the cassette only, a miss is an error
+    REPLAY,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CassetteConfig {
+    pub mode: CassetteMode,
+    pub path: String,
+}
+
+/// Recorded request/response pair
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CassetteEntry {
+    pub request: MessageRequest,
+    pub response: MessageResponse,
+}
+
+/// ChatModel decorator that records exchanges to, or replays them from, a file
+pub struct Cassette<M> {
+    inner: M,
+    config: CassetteConfig,
+    // request key -> entry
+    entries: Mutex<HashMap<String, CassetteEntry>>,
+}
+
+impl<M: ChatModel + Send + Sync> Cassette<M> {
+    pub fn new(inner: M, config: CassetteConfig) -> Self {
+        let entries = read_json(&config.path);
+        Self {
+            inner,
+            config,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// SHA-256 of the request with whitespace in message contents normalized
+    pub fn key(req: &MessageRequest) -> String {
+        let mut normalized = req.clone();
+        for m in normalized.messages.iter_mut() {
+            m.content = m.content.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+
+        // serialization of a derived struct keeps field order stable
+        let json = serde_json::to_vec(&normalized).unwrap_or_default();
+        Sha256::digest(&json)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel + Send + Sync> ChatModel for Cassette<M> {
+    async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
+        let key = Self::key(req);
+
+        if self.config.mode == CassetteMode::REPLAY {
+            return self
+                .entries
+                .lock()
+                .unwrap()
+                .get(&key)
+                .map(|e| e.response.clone())
+                .ok_or_else(|| {
+                    error!("Cassette {} has no entry for {}", self.config.path, key);
+                    ChatModelError::CassetteMiss(key)
+                });
+        }
+
+        let response = self.inner.generate(req).await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            key.clone(),
+            CassetteEntry {
+                request: req.clone(),
+                response: response.clone(),
+            },
+        );
+        match write_json(&self.config.path, &*entries) {
+            Ok(()) => debug!("Recorded {} to {}", key, self.config.path),
+            Err(err) => error!("Failed to write cassette {}: {}", self.config.path, err),
+        }
+
+        Ok(response)
+    }
+
+    fn label(&self) -> String {
+        self.inner.label()
+    }
+}
@@ -0,0 +1,140 @@
+use async_trait::async_trait;
+use log::warn;
+use rand::Rng;
+use std::{future::Future, time::Duration};
+use tokio::time::{sleep, timeout};
+
+use crate::{
+    chat_model::{
+        core::{ChatModel, ChatModelError, ChatStream, MessageRequest, MessageResponse},
+        providers::build_model,
+    },
+    config::{Account, ModelTarget, RetryConfig},
+};
+
+/// ChatModel wrapper that retries, times out and falls back to other models.
+pub struct Resilient {
+    // (label, model) in the order they are tried
+    chain: Vec<(String, Box<dyn ChatModel + Send + Sync>)>,
+    policy: RetryConfig,
+}
+
+impl Resilient {
+    pub fn new(
+        chain: Vec<(String, Box<dyn ChatModel + Send + Sync>)>,
+        policy: RetryConfig,
+    ) -> Self {
+        Self { chain, policy }
+    }
+
+    /// Build the chain from the account's primary model and its fallbacks.
+    pub fn from_account(account: &Account, client: &reqwest::Client) -> Self {
+        let primary = ModelTarget {
+            provider: account.provider.clone(),
+            model: account.gpt_model.clone(),
+        };
+
+        let chain = std::iter::once(&primary)
+            .chain(account.fallback_models.iter())
+            .map(|t| {
+                (
+                    format!("{:?}/{}", t.provider, t.model),
+                    build_model(&account.account_name, t, client),
+                )
+            })
+            .collect();
+
+        Self::new(chain, account.retry.clone())
+    }
+
+    /// Exponential backoff with full jitter.
+    fn backoff(&self, attempt: usize) -> Duration {
+        let exp = self
+            .policy
+            .base_delay_ms
+            .saturating_mul(1u64 << attempt.min(16));
+        let cap = exp.min(self.policy.max_delay_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+    }
+
+    /// Run `call` with the per-request timeout, retrying retryable errors.
+    async fn try_model<T, F, Fut>(&self, label: &str, call: F) -> Result<T, ChatModelError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ChatModelError>>,
+    {
+        let limit = Duration::from_secs(self.policy.request_timeout);
+        let max_delay = Duration::from_millis(self.policy.max_delay_ms);
+        let mut attempt = 0;
+
+        loop {
+            let err = match timeout(limit, call()).await {
+                Ok(Ok(resp)) => return Ok(resp),
+                Ok(Err(err)) => err,
+                Err(_) => ChatModelError::Timeout(limit),
+            };
+
+            if !err.is_retryable() || attempt >= self.policy.max_retries {
+                return Err(err);
+            }
+
+            let delay = match err.retry_after() {
+                // don't stall the cycle, let the next model take over
+                Some(wait) if wait > max_delay => return Err(err),
+                Some(wait) => wait,
+                None => self.backoff(attempt),
+            };
+
+            attempt += 1;
+            warn!(
+                "{} failed ({}), retry {}/{} in {:?}",
+                label, err, attempt, self.policy.max_retries, delay
+            );
+            sleep(delay).await;
+        }
+    }
+}
+
+#[async_trait]
+impl ChatModel for Resilient {
+    async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
+        let mut last_err = ChatModelError::Api("no chat model configured".into());
+
+        for (label, model) in &self.chain {
+            match self.try_model(label, || model.generate(req)).await {
+                Ok(resp) => return Ok(resp),
+                Err(err) => {
+                    warn!("{} gave up: {}", label, err);
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    fn label(&self) -> String {
+        self.chain
+            .iter()
+            .map(|(label, _)| label.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Retries and fallbacks only cover opening the stream, not errors mid-stream.
+    async fn generate_stream(&self, req: &MessageRequest) -> Result<ChatStream, ChatModelError> {
+        let mut last_err = ChatModelError::Api("no chat model configured".into());
+
+        for (label, model) in &self.chain {
+            match self.try_model(label, || model.generate_stream(req)).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    warn!("{} gave up: {}", label, err);
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+}
@@ -1,3 +1,5 @@
 pub mod core;
+pub mod middleware;
 pub mod providers;
 pub mod service;
+pub mod tools;
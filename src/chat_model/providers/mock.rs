@@ -0,0 +1,330 @@
+use async_trait::async_trait;
+use log::warn;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::chat_model::core::{ChatModel, ChatModelError, MessageRequest, MessageResponse, Usage};
+
+/// How the mock picks its answer
+#[derive(Debug, Clone, Deserialize, PartialEq, Default)]
+pub enum MockMode {
+    /// Cycle through `responses`
+    #[default]
+    SCRIPTED,
+    /// Return the rendered prompt
+    ECHO,
+    /// First rule whose pattern matches the last message
+    CANNED,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct MockRule {
+    pub pattern: String,
+    pub responses: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MockErrorKind {
+    RateLimited,
+    Auth,
+    BadRequest,
+    Server,
+    Timeout,
+}
+
+/// Mock provider settings, usable from YAML (`Mock:` in the config) or code
+#[derive(Debug, Clone, Deserialize, Default, PartialEq)]
+#[serde(default)]
+pub struct MockConfig {
+    pub mode: MockMode,
+    pub responses: Vec<String>,
+    pub rules: Vec<MockRule>,
+    /// answer when no rule matches
+    pub fallback: Option<String>,
+    /// delay before every answer
+    pub latency_ms: u64,
+    /// the first N calls fail
+    pub fail_first: usize,
+    /// every Nth call fails
+    pub fail_every: Option<usize>,
+    pub error: Option<MockErrorKind>,
+    /// requests kept for [`MockChatModel::requests`], the oldest are dropped first
+    pub record_requests: usize,
+}
+
+/// Mocks by `account/model`, kept across cycles so scripts and failures continue
+static SHARED: Lazy<Mutex<HashMap<String, Arc<MockChatModel>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The mock of the account for `model`, created again when `config` changed.
+pub fn shared(account: &str, model: &str, config: &MockConfig) -> Arc<MockChatModel> {
+    let mut mocks = SHARED.lock().unwrap();
+    let key = format!("{}/{}", account.to_lowercase(), model);
+    match mocks.get(&key) {
+        Some(mock) if mock.config == *config => mock.clone(),
+        _ => {
+            let mock = Arc::new(MockChatModel::new(config.clone()));
+            mocks.insert(key, mock.clone());
+            mock
+        }
+    }
+}
+
+/// Deterministic ChatModel for tests and demos
+pub struct MockChatModel {
+    config: MockConfig,
+    rules: Vec<(Regex, Vec<String>)>,
+    calls: AtomicUsize,
+    requests: Mutex<VecDeque<MessageRequest>>,
+}
+
+impl MockChatModel {
+    pub fn new(config: MockConfig) -> Self {
+        let rules = config
+            .rules
+            .iter()
+            .filter_map(|r| match Regex::new(&r.pattern) {
+                Ok(re) => Some((re, r.responses.clone())),
+                Err(err) => {
+                    warn!("Skipping mock rule {}: {}", r.pattern, err);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            config,
+            rules,
+            calls: AtomicUsize::new(0),
+            requests: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self, serde_yml::Error> {
+        Ok(Self::new(serde_yml::from_str(yaml)?))
+    }
+
+    /// Answers with the given responses in order, then starts over.
+    pub fn scripted<S: Into<String>>(responses: impl IntoIterator<Item = S>) -> Self {
+        Self::new(MockConfig {
+            responses: responses.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Answers with the rendered prompt.
+    pub fn echo() -> Self {
+        Self::new(MockConfig {
+            mode: MockMode::ECHO,
+            ..Default::default()
+        })
+    }
+
+    /// Keep the last `limit` requests for [`Self::requests`].
+    pub fn recording(mut self, limit: usize) -> Self {
+        self.config.record_requests = limit;
+        self
+    }
+
+    /// The last `record_requests` requests, oldest first
+    pub fn requests(&self) -> Vec<MessageRequest> {
+        self.requests.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn call_count(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    fn should_fail(&self, call: usize) -> bool {
+        call < self.config.fail_first
+            || self
+                .config
+                .fail_every
+                .is_some_and(|n| n > 0 && call % n == n - 1)
+    }
+
+    fn error(&self) -> ChatModelError {
+        let message = "mock error".to_string();
+        match self.config.error.as_ref().unwrap_or(&MockErrorKind::Server) {
+            MockErrorKind::RateLimited => ChatModelError::RateLimited {
+                message,
+                retry_after: None,
+            },
+            MockErrorKind::Auth => ChatModelError::Auth(message),
+            MockErrorKind::BadRequest => ChatModelError::BadRequest(message),
+            MockErrorKind::Server => ChatModelError::Server {
+                status: 500,
+                message,
+            },
+            MockErrorKind::Timeout => {
+                ChatModelError::Timeout(Duration::from_millis(self.config.latency_ms))
+            }
+        }
+    }
+
+    fn answer(&self, req: &MessageRequest, call: usize) -> Result<String, ChatModelError> {
+        let pick = |list: &[String]| list.get(call % list.len().max(1)).cloned();
+
+        let text = match self.config.mode {
+            MockMode::SCRIPTED => pick(&self.config.responses),
+            MockMode::ECHO => Some(
+                req.messages
+                    .iter()
+                    .map(|m| format!("{}: {}", m.role, m.content))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            MockMode::CANNED => {
+                let last = req
+                    .messages
+                    .last()
+                    .map(|m| m.content.as_str())
+                    .unwrap_or_default();
+                self.rules
+                    .iter()
+                    .find(|(re, _)| re.is_match(last))
+                    .and_then(|(_, responses)| pick(responses))
+                    .or_else(|| self.config.fallback.clone())
+            }
+        };
+
+        text.ok_or_else(|| ChatModelError::Api("mock has no response for this request".into()))
+    }
+}
+
+#[async_trait]
+impl ChatModel for MockChatModel {
+    async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+        if self.config.record_requests > 0 {
+            let mut requests = self.requests.lock().unwrap();
+            if requests.len() == self.config.record_requests {
+                requests.pop_front();
+            }
+            requests.push_back(req.clone());
+        }
+
+        if self.config.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.config.latency_ms)).await;
+        }
+        if self.should_fail(call) {
+            return Err(self.error());
+        }
+
+        let text = self.answer(req, call)?;
+
+        // Rough estimate, 4 chars per token
+        let prompt_chars: usize = req.messages.iter().map(|m| m.content.len()).sum();
+        let usage = Usage {
+            prompt_tokens: (prompt_chars / 4) as u64,
+            completion_tokens: (text.len() / 4) as u64,
+        };
+
+        Ok(MessageResponse {
+            text,
+            usage: Some(usage),
+            model: Some("mock".into()),
+            tool_calls: Vec::new(),
+            cached: false,
+        })
+    }
+
+    fn label(&self) -> String {
+        "MOCK/mock".into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_model::core::Message;
+
+    fn request(text: &str) -> MessageRequest {
+        MessageRequest::new(vec![Message::new("user", text)])
+    }
+
+    async fn answers(mock: &MockChatModel, texts: &[&str]) -> Vec<Result<String, String>> {
+        let mut answers = Vec::new();
+        for text in texts {
+            let resp = mock.generate(&request(text)).await;
+            answers.push(resp.map(|r| r.text).map_err(|e| e.to_string()));
+        }
+        answers
+    }
+
+    #[tokio::test]
+    async fn scripted_starts_over() {
+        let mock = MockChatModel::scripted(["a", "b"]);
+        let answers = answers(&mock, &["x", "x", "x"]).await;
+        assert_eq!(answers, [Ok("a".into()), Ok("b".into()), Ok("a".into())]);
+        assert_eq!(mock.call_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn canned_rules_and_fallback() {
+        let mock = MockChatModel::from_yaml(
+            "mode: CANNED\nrules:\n  - pattern: \"(?i)hello\"\n    responses: [hi]\nfallback: lol",
+        )
+        .unwrap();
+        let answers = answers(&mock, &["Hello there", "what"]).await;
+        assert_eq!(answers, [Ok("hi".into()), Ok("lol".into())]);
+    }
+
+    #[tokio::test]
+    async fn failures() {
+        let mock = MockChatModel::new(MockConfig {
+            responses: vec!["ok".into()],
+            fail_first: 1,
+            fail_every: Some(3),
+            error: Some(MockErrorKind::BadRequest),
+            ..Default::default()
+        });
+        let answers = answers(&mock, &["x", "x", "x", "x"]).await;
+        assert!(answers[0].is_err());
+        assert!(answers[1].is_ok());
+        assert!(answers[2]
+            .as_ref()
+            .is_err_and(|e| e.starts_with("Bad request")));
+        assert!(answers[3].is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_are_capped() {
+        let mock = MockChatModel::echo();
+        answers(&mock, &["one"]).await;
+        assert!(mock.requests().is_empty());
+
+        let mock = MockChatModel::echo().recording(2);
+        let answers = answers(&mock, &["one", "two", "three"]).await;
+        assert_eq!(answers[2], Ok("user: three".into()));
+        let recorded: Vec<String> = mock
+            .requests()
+            .iter()
+            .map(|r| r.messages[0].content.clone())
+            .collect();
+        assert_eq!(recorded, ["two", "three"]);
+    }
+
+    #[test]
+    fn shared_until_config_changes() {
+        let config = MockConfig::default();
+        let first = shared("Tester", "mock", &config);
+        assert!(Arc::ptr_eq(&first, &shared("tester", "mock", &config)));
+
+        let changed = MockConfig {
+            latency_ms: 1,
+            ..Default::default()
+        };
+        assert!(!Arc::ptr_eq(&first, &shared("tester", "mock", &changed)));
+    }
+}
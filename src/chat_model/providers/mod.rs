@@ -1 +1,19 @@
+use crate::{
+    chat_model::core::ChatModel,
+    config::{ModelTarget, Provider, CONFIG},
+};
+
+pub mod mock;
 pub mod openai;
+
+/// Build a chat model for the given provider/model pair of `account`.
+pub fn build_model(
+    account: &str,
+    target: &ModelTarget,
+    client: &reqwest::Client,
+) -> Box<dyn ChatModel + Send + Sync> {
+    match target.provider {
+        Provider::OPENAI => Box::new(openai::OpenAI::new(target.model.clone(), client.clone())),
+        Provider::MOCK => Box::new(mock::shared(account, &target.model, &CONFIG.get().mock)),
+    }
+}
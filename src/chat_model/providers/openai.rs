@@ -1,72 +1,304 @@
 use async_trait::async_trait;
-use std::borrow::Cow;
+use futures_util::{stream, StreamExt};
+use std::{borrow::Cow, collections::VecDeque};
 
 use crate::{
-    chat_model::core::{ChatModel, ChatModelError, MessageRequest, MessageResponse},
+    chat_model::core::{
+        ChatModel, ChatModelError, ChatStream, Message, MessageRequest, MessageResponse,
+        StreamEvent, ToolCall, Usage,
+    },
     config::CONFIG,
 };
 
 pub struct OpenAI {
     model: Cow<'static, str>,
+    client: reqwest::Client,
 }
 
 impl OpenAI {
-    pub fn new<S: Into<Cow<'static, str>>>(model: S) -> Self {
+    pub fn new<S: Into<Cow<'static, str>>>(model: S, client: reqwest::Client) -> Self {
         Self {
             model: model.into(),
+            client,
         }
     }
+
+    /// Build request payload for Chat Completions
+    /// { model, messages: [{role, content}, ...], tools: [...] }
+    fn build_body(&self, req: &MessageRequest) -> serde_json::Value {
+        let messages_json: Vec<serde_json::Value> = req.messages.iter().map(message_json).collect();
+
+        let mut body = serde_json::json!({
+            "model": self.model.as_ref(),
+            "messages": messages_json,
+        });
+
+        let params = &req.params;
+        if let Some(v) = params.temperature {
+            body["temperature"] = serde_json::json!(v);
+        }
+        if let Some(v) = params.top_p {
+            body["top_p"] = serde_json::json!(v);
+        }
+        if let Some(v) = params.max_tokens {
+            body["max_completion_tokens"] = serde_json::json!(v);
+        }
+        if let Some(v) = params.presence_penalty {
+            body["presence_penalty"] = serde_json::json!(v);
+        }
+        if let Some(v) = params.frequency_penalty {
+            body["frequency_penalty"] = serde_json::json!(v);
+        }
+
+        if !req.tools.is_empty() {
+            let tools_json: Vec<serde_json::Value> = req
+                .tools
+                .iter()
+                .map(|t| {
+                    serde_json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": t.name,
+                            "description": t.description,
+                            "parameters": t.parameters,
+                        },
+                    })
+                })
+                .collect();
+            body["tools"] = serde_json::json!(tools_json);
+        }
+
+        body
+    }
+
+    /// POST the payload and turn non-success statuses into errors.
+    async fn send(&self, body: &serde_json::Value) -> Result<reqwest::Response, ChatModelError> {
+        let resp = self
+            .client
+            .post("https://api.openai.com/v1/chat/completions")
+            .bearer_auth(&CONFIG.get().openai.api_key)
+            .header("Content-Type", "application/json")
+            .json(body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let headers = resp.headers().clone();
+            let message = resp.text().await.unwrap_or_default();
+            return Err(ChatModelError::from_response(status, &headers, message));
+        }
+
+        Ok(resp)
+    }
 }
 
 #[async_trait]
 impl ChatModel for OpenAI {
     async fn generate(&self, req: &MessageRequest) -> Result<MessageResponse, ChatModelError> {
-        // Read model
-        let model = self.model.as_ref();
+        let body = self.build_body(req);
+        let resp = self.send(&body).await?;
+
+        let v: serde_json::Value = resp.json().await?;
+
+        // Extract tool calls if the model asked for any
+        let tool_calls: Vec<ToolCall> = v
+            .pointer("/choices/0/message/tool_calls")
+            .and_then(|x| x.as_array())
+            .map(|calls| calls.iter().filter_map(parse_tool_call).collect())
+            .unwrap_or_default();
+
+        // Extract assistant message content, null when only tools are called
+        let text = match v
+            .pointer("/choices/0/message/content")
+            .and_then(|x| x.as_str())
+        {
+            Some(s) => s.to_string(),
+            None if !tool_calls.is_empty() => String::new(),
+            None => {
+                return Err(ChatModelError::Api(
+                    "missing choices[0].message.content".into(),
+                ))
+            }
+        };
 
-        // Build request payload for Chat Completions
-        // { model, messages: [{role, content}, ...] }
-        let messages_json: Vec<serde_json::Value> = req
-            .messages
+        // Extract usage tokens if present
+        let usage = parse_usage(&v);
+        let model = v.get("model").and_then(|x| x.as_str()).map(String::from);
+
+        Ok(MessageResponse {
+            text,
+            usage,
+            model,
+            tool_calls,
+            cached: false,
+        })
+    }
+
+    fn label(&self) -> String {
+        format!("OPENAI/{}", self.model)
+    }
+
+    async fn generate_stream(&self, req: &MessageRequest) -> Result<ChatStream, ChatModelError> {
+        let mut body = self.build_body(req);
+        body["stream"] = serde_json::json!(true);
+        body["stream_options"] = serde_json::json!({ "include_usage": true });
+
+        let resp = self.send(&body).await?;
+        let state = SseState {
+            bytes: Box::pin(resp.bytes_stream()),
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            usage: None,
+            model: None,
+            finished: false,
+        };
+
+        Ok(Box::pin(stream::unfold(state, |mut state| async move {
+            let event = state.next_event().await?;
+            Some((event, state))
+        })))
+    }
+}
+
+fn message_json(m: &Message) -> serde_json::Value {
+    let mut v = serde_json::json!({
+        "role": m.role,
+        "content": m.content,
+    });
+
+    if !m.tool_calls.is_empty() {
+        let calls: Vec<serde_json::Value> = m
+            .tool_calls
             .iter()
-            .map(|m| {
+            .map(|c| {
                 serde_json::json!({
-                    "role": m.role,
-                    "content": m.content,
+                    "id": c.id,
+                    "type": "function",
+                    "function": { "name": c.name, "arguments": c.arguments },
                 })
             })
             .collect();
+        v["tool_calls"] = serde_json::json!(calls);
+    }
+    if let Some(id) = &m.tool_call_id {
+        v["tool_call_id"] = serde_json::json!(id);
+    }
 
-        let body = serde_json::json!({
-            "model": model,
-            "messages": messages_json,
-        });
+    v
+}
 
-        let client = reqwest::Client::new();
-        let resp = client
-            .post("https://api.openai.com/v1/chat/completions")
-            .bearer_auth(&CONFIG.openai.api_key)
-            .header("Content-Type", "application/json")
-            .json(&body)
-            .send()
-            .await?
-            .error_for_status()?;
+fn parse_usage(v: &serde_json::Value) -> Option<Usage> {
+    let usage = v.get("usage").filter(|u| u.is_object())?;
+    let count = |key: &str| usage.get(key).and_then(|x| x.as_u64()).unwrap_or_default();
 
-        let v: serde_json::Value = resp.json().await?;
+    Some(Usage {
+        prompt_tokens: count("prompt_tokens"),
+        completion_tokens: count("completion_tokens"),
+    })
+}
 
-        // Extract assistant message content
-        let text = v
-            .pointer("/choices/0/message/content")
+fn parse_tool_call(v: &serde_json::Value) -> Option<ToolCall> {
+    Some(ToolCall {
+        id: v.get("id")?.as_str()?.to_string(),
+        name: v.pointer("/function/name")?.as_str()?.to_string(),
+        arguments: v
+            .pointer("/function/arguments")
             .and_then(|x| x.as_str())
-            .map(|s| s.to_string())
-            .ok_or_else(|| ChatModelError::Api("missing choices[0].message.content".into()))?;
+            .unwrap_or("{}")
+            .to_string(),
+    })
+}
 
-        // Extract usage tokens if present
-        let used_tokens = v
-            .pointer("/usage/total_tokens")
-            .and_then(|x| x.as_u64())
-            .map(|n| n as usize);
+type ByteStream =
+    std::pin::Pin<Box<dyn futures_util::Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>;
+
+/// Decoder for the server-sent events of a streamed completion
+struct SseState {
+    bytes: ByteStream,
+    buffer: Vec<u8>,
+    pending: VecDeque<StreamEvent>,
+    usage: Option<Usage>,
+    model: Option<String>,
+    finished: bool,
+}
+
+impl SseState {
+    async fn next_event(&mut self) -> Option<Result<StreamEvent, ChatModelError>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.finished {
+                return None;
+            }
 
-        Ok(MessageResponse { text, used_tokens })
+            // Process complete lines before reading more bytes
+            if let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                if let Err(err) = self.handle_line(line.trim()) {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+                continue;
+            }
+
+            match self.bytes.next().await {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(err)) => {
+                    self.finished = true;
+                    return Some(Err(err.into()));
+                }
+                // connection closed without [DONE]
+                None => self.finish(),
+            }
+        }
+    }
+
+    fn handle_line(&mut self, line: &str) -> Result<(), ChatModelError> {
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            return Ok(());
+        };
+        if data == "[DONE]" {
+            self.finish();
+            return Ok(());
+        }
+
+        let v: serde_json::Value = serde_json::from_str(data)?;
+        if let Some(err) = v.pointer("/error/message").and_then(|x| x.as_str()) {
+            return Err(ChatModelError::Api(err.to_string()));
+        }
+
+        if let Some(delta) = v
+            .pointer("/choices/0/delta/content")
+            .and_then(|x| x.as_str())
+            .filter(|s| !s.is_empty())
+        {
+            self.pending
+                .push_back(StreamEvent::Delta(delta.to_string()));
+        }
+
+        if self.model.is_none() {
+            self.model = v.get("model").and_then(|x| x.as_str()).map(String::from);
+        }
+
+        // Usage arrives in the final chunk with empty choices
+        if let Some(usage) = parse_usage(&v) {
+            self.usage = Some(usage);
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.pending.push_back(StreamEvent::Done {
+                usage: self.usage,
+                model: self.model.take(),
+                cached: false,
+            });
+        }
     }
 }
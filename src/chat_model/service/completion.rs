@@ -1,83 +1,330 @@
-use log::info;
+use chrono::Utc;
+use futures_util::StreamExt;
+use log::{debug, info, warn};
 
 use crate::{
     chat_model::{
-        core::{ChatModel, Message, MessageRequest},
-        service::types::CompletionError,
+        core::{
+            CachePolicy, ChatModel, Message, MessageRequest, MessageResponse, StreamEvent, Usage,
+        },
+        middleware::{cache::ResponseCache, retry::Resilient},
+        providers::build_model,
+        service::{dedup, guard, instruction, postprocess, types::CompletionError},
+        tools::ToolRegistry,
     },
-    config::Account,
+    config::{Account, ModelTarget},
+    sanctions::SANCTIONS,
+    store::{Generation, STORE},
     twitch::UserMsg,
+    usage::USAGE,
+    viewers,
 };
-use std::collections::HashMap;
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::time::Instant;
 
+/// Upper bound of model calls while resolving tool calls
+const MAX_TOOL_ROUNDS: usize = 5;
+
+/// Indices of `user_messages` the injection classifier flags, empty unless the
+/// instruction sets `guard.classifier`.
+///
+/// Called once per cycle, regenerations reuse the result.
+pub async fn classify_history<T>(
+    user_messages: &[UserMsg],
+    account: &Account,
+    instruction_path: &str,
+    completion_model: &T,
+    client: &reqwest::Client,
+) -> Vec<usize>
+where
+    T: ChatModel + Sync,
+{
+    let guard = match instruction::get_instruction(account, instruction_path) {
+        Ok(instruction) if instruction.guard.classifier => instruction.guard.clone(),
+        _ => return Vec::new(),
+    };
+
+    let ctx = instruction::build_context(user_messages, account);
+    match &guard.classifier_model {
+        Some(model) => {
+            let target = ModelTarget {
+                provider: account.provider.clone(),
+                model: model.clone(),
+            };
+            let classifier = Resilient::new(
+                vec![(
+                    format!("{:?}/{}", target.provider, target.model),
+                    build_model(&account.account_name, &target, client),
+                )],
+                account.retry.clone(),
+            );
+            guard::classify(&classifier, account, &ctx.history).await
+        }
+        None => guard::classify(completion_model, account, &ctx.history).await,
+    }
+}
+
+/// Generate a chat message with the account's instruction file `instruction_path`.
+///
+/// `history` is stored chat with its row ids, see [`crate::store::Store::recent_messages`].
+/// `flagged` are indices of `history` from [`classify_history`].
+/// `cache` only applies to accounts with `use_cache` enabled.
 pub async fn generate_chat<T>(
-    user_messages: &Vec<UserMsg>,
+    history: &[(i64, UserMsg)],
+    flagged: &[usize],
     account: &Account,
+    instruction_path: &str,
     completion_model: &T,
+    tools: &ToolRegistry,
+    cache: CachePolicy,
 ) -> Result<String, CompletionError>
 where
-    T: ChatModel,
+    T: ChatModel + Sync,
 {
-    // Load template
-    let instruction_path = resolve_instruction_path(&account.instruction).ok_or_else(|| {
-        CompletionError::PathResolve(format!("unable to resolve path: {}", account.instruction))
-    })?;
-
-    // Read and parse template
-    let json_string = fs::read_to_string(&instruction_path)?;
-    let json: Vec<Message> = serde_json::from_str(&json_string)?;
-
-    // Build placeholder map, see instructions/README.md
-    let history = user_messages
-        .iter()
-        .filter(|m| m.sender != account.account_name)
-        .map(|m| m.message.as_str())
-        .collect::<Vec<_>>()
-        .join(",");
-
-    let mut placeholders: HashMap<&str, String> = HashMap::new();
-    placeholders.insert("history", history);
-    placeholders.insert("account_name", account.account_name.clone());
-    placeholders.insert("channel", account.channel.clone());
-
-    // Apply placeholders to instruction
-    let messages: Vec<Message> = json
-        .into_iter()
-        .map(|mut m| {
-            m.content = replace_placeholders(&m.content, &placeholders);
-            m
-        })
-        .collect();
+    let (message_ids, user_messages): (Vec<i64>, Vec<UserMsg>) = history.iter().cloned().unzip();
+
+    // Load template and render it, see instructions/README.md
+    let instruction = instruction::get_instruction(account, instruction_path)?;
+    let mut ctx = instruction::build_context(&user_messages, account);
+    ctx.deleted_messages = SANCTIONS.deleted_messages(account);
+    viewers::annotate(&mut ctx, &user_messages);
+    for i in flagged {
+        if let Some(entry) = ctx.history.get_mut(*i) {
+            entry.flagged = true;
+        }
+    }
+    let messages = instruction::render_messages(&instruction, &ctx)?;
 
     // Build request and generate completion
-    let req = MessageRequest { messages };
-    let resp = completion_model.generate(&req).await?;
+    let mut req = MessageRequest::new(messages);
+    req.params = instruction.params.clone();
+    req.cache = if account.use_cache {
+        cache
+    } else {
+        CachePolicy::Bypass
+    };
 
+    // Regenerate while the answer repeats what was recently sent
+    let mut regenerations = 0;
+    loop {
+        let text = complete(req.clone(), &message_ids, account, completion_model, tools).await?;
+        let Some(similar) = dedup::find_similar(account, &text) else {
+            return Ok(text);
+        };
+        STORE.set_outcome(account, &text, "repeated");
+        if regenerations >= account.dedup.retries {
+            return Err(CompletionError::Repeated { text, similar });
+        }
+
+        warn!(
+            "{} repeats {:?}, regenerating",
+            account.account_name, similar
+        );
+        regenerations += 1;
+        req.messages
+            .push(Message::new("system", dedup::hint(&similar)));
+    }
+}
+
+/// One generation, with tool calls resolved and post-processing applied
+///
+/// It's stored with the `message_ids` of the chat it was generated from.
+async fn complete<T>(
+    mut req: MessageRequest,
+    message_ids: &[i64],
+    account: &Account,
+    completion_model: &T,
+    tools: &ToolRegistry,
+) -> Result<String, CompletionError>
+where
+    T: ChatModel + Sync,
+{
+    let started = Instant::now();
+    let prompt = req.messages.clone();
+    let mut cache_keys = Vec::new();
+    let resp = if tools.is_empty() {
+        cache_keys.extend(cache_key(&req, completion_model));
+        stream_completion(&req, account, completion_model).await?
+    } else {
+        req.tools = tools.definitions();
+        resolve_tool_calls(req, account, completion_model, tools, &mut cache_keys).await?
+    };
+
+    // budgets must count every generation, estimate when the provider reports nothing
+    let usage = if resp.cached {
+        Usage::default()
+    } else {
+        resp.usage.unwrap_or_else(|| {
+            let estimate = Usage {
+                prompt_tokens: instruction::estimate_tokens(&prompt) as u64,
+                completion_tokens: instruction::estimate_tokens(&[Message::new(
+                    "assistant",
+                    resp.text.clone(),
+                )]) as u64,
+            };
+            debug!("no usage reported, estimated {} tokens", estimate.total());
+            estimate
+        })
+    };
     info!(
-        "answer: {} - used tokens: {}",
+        "answer: {} - used tokens: {} (prompt {}, completion {})",
         resp.text,
-        resp.used_tokens.unwrap_or_default()
+        usage.total(),
+        usage.prompt_tokens,
+        usage.completion_tokens
     );
 
-    Ok(resp.text)
+    // answers from the response cache cost nothing
+    if !resp.cached {
+        let model = resp.model.as_deref().unwrap_or(&account.gpt_model);
+        USAGE.record(&account.account_name, model, &usage);
+    }
+
+    let text = postprocess::apply_all(&account.post_process, &resp.text, &account.account_name);
+    STORE.record_generation(&Generation {
+        id: 0,
+        account: account.account_name.clone(),
+        channel: account.channel.clone(),
+        prompt,
+        output: text.clone(),
+        model: resp
+            .model
+            .clone()
+            .unwrap_or_else(|| account.gpt_model.clone()),
+        usage,
+        latency_ms: started.elapsed().as_millis() as u64,
+        outcome: text
+            .is_empty()
+            .then(|| "empty after post-processing".into()),
+        created_at: Utc::now(),
+        message_ids: message_ids.to_vec(),
+        cache_keys,
+    });
+    if text.is_empty() {
+        return Err(CompletionError::EmptyAfterPostProcess(resp.text));
+    }
+    if text != resp.text {
+        debug!("post-processed: {}", text);
+    }
+
+    Ok(text)
+}
+
+/// Key a response cache would store the answer to `req` under
+fn cache_key<T: ChatModel>(req: &MessageRequest, completion_model: &T) -> Option<String> {
+    (req.cache == CachePolicy::Use).then(|| ResponseCache::key(&completion_model.label(), req))
 }
 
-fn resolve_instruction_path(instruction: &str) -> Option<PathBuf> {
-    let p = Path::new(instruction);
-    if p.is_absolute() || p.exists() {
-        return Some(p.to_path_buf());
+/// Stream the answer, stopping early once it can no longer be sent.
+///
+/// Usage of a stream stopped early is estimated from what was received, it only comes last.
+async fn stream_completion<T>(
+    req: &MessageRequest,
+    account: &Account,
+    completion_model: &T,
+) -> Result<MessageResponse, CompletionError>
+where
+    T: ChatModel + Sync,
+{
+    let started = Instant::now();
+    let mut stream = completion_model.generate_stream(req).await?;
+
+    let mut text = String::new();
+    let mut usage = None;
+    let mut model = None;
+    let mut first_token = None;
+    let mut cached = false;
+    while let Some(event) = stream.next().await {
+        match event? {
+            StreamEvent::Delta(delta) => {
+                first_token.get_or_insert_with(|| started.elapsed());
+                text.push_str(&delta);
+
+                // No point in generating what can't be sent
+                if text.chars().count() > account.max_message_length {
+                    debug!(
+                        "Stopped generation at {} chars for {}",
+                        account.max_message_length, account.channel
+                    );
+                    usage = Some(Usage {
+                        prompt_tokens: instruction::estimate_tokens(&req.messages) as u64,
+                        completion_tokens: instruction::estimate_tokens(&[Message::new(
+                            "assistant",
+                            text.as_str(),
+                        )]) as u64,
+                    });
+                    text = text.chars().take(account.max_message_length).collect();
+                    break;
+                }
+            }
+            StreamEvent::Done {
+                usage: u,
+                model: m,
+                cached: c,
+            } => {
+                usage = u;
+                model = m;
+                cached = c;
+            }
+        }
     }
+    // closes the connection, so the provider stops generating
+    drop(stream);
+
+    debug!(
+        "time to first token: {:?}, total: {:?}",
+        first_token.unwrap_or_default(),
+        started.elapsed()
+    );
 
-    return None;
+    Ok(MessageResponse {
+        text,
+        usage,
+        model,
+        tool_calls: Vec::new(),
+        cached,
+    })
 }
 
-fn replace_placeholders(input: &str, vars: &HashMap<&str, String>) -> String {
-    let mut out = input.to_string();
-    for (k, v) in vars.iter() {
-        let from = format!("{{{}}}", k);
-        out = out.replace(&from, v);
+/// Call the model, run requested tools and feed results back until it answers.
+///
+/// The cache key of every round is added to `cache_keys`.
+async fn resolve_tool_calls<T>(
+    mut req: MessageRequest,
+    account: &Account,
+    completion_model: &T,
+    tools: &ToolRegistry,
+    cache_keys: &mut Vec<String>,
+) -> Result<MessageResponse, CompletionError>
+where
+    T: ChatModel + Sync,
+{
+    let mut usage: Option<Usage> = None;
+    // true while every round came from the response cache
+    let mut cached = true;
+
+    for _ in 0..MAX_TOOL_ROUNDS {
+        cache_keys.extend(cache_key(&req, completion_model));
+        let mut resp = completion_model.generate(&req).await?;
+        cached &= resp.cached;
+        if let Some(u) = resp.usage.as_ref().filter(|_| !resp.cached) {
+            usage.get_or_insert_with(Usage::default).add(u);
+        }
+
+        if resp.tool_calls.is_empty() {
+            resp.text = resp.text.chars().take(account.max_message_length).collect();
+            resp.usage = usage;
+            resp.cached = cached;
+            return Ok(resp);
+        }
+
+        let mut results = Vec::with_capacity(resp.tool_calls.len());
+        for call in &resp.tool_calls {
+            let output = tools.call(call).await;
+            results.push(Message::tool_result(&call.id, output));
+        }
+        req.messages.push(Message::tool_calls(resp.tool_calls));
+        req.messages.extend(results);
     }
-    out
+
+    Err(CompletionError::ToolRounds(MAX_TOOL_ROUNDS))
 }
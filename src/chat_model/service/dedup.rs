@@ -0,0 +1,114 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    config::Account,
+    twitch::{room_state, sent_log::sent_to_channel},
+};
+
+/// Message of the channel's recent ones that `text` is too close to.
+///
+/// In unique chat (r9k) identical messages are found even with dedup disabled.
+pub fn find_similar(account: &Account, text: &str) -> Option<String> {
+    let config = &account.dedup;
+    let threshold = if config.enabled {
+        config.threshold
+    } else if room_state::unique_chat(account) {
+        1.0
+    } else {
+        return None;
+    };
+
+    let window = Duration::from_secs(config.window_secs);
+    sent_to_channel(&account.channel, window)
+        .into_iter()
+        .rev()
+        .map(|m| m.text)
+        .find(|sent| similarity(sent, text) >= threshold)
+}
+
+/// Instruction appended when regenerating after a repeat
+pub fn hint(similar: &str) -> String {
+    format!(
+        "Your reply was too similar to a message that was already sent: \"{}\". \
+         Do not repeat it, write something different.",
+        similar
+    )
+}
+
+/// Lowercase letters and digits only, so punctuation, spacing and emoji don't count
+fn normalize(text: &str) -> Vec<char> {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// 1.0 for the same text or the same normalized text, otherwise the Dice coefficient
+/// of character bigrams.
+///
+/// Text without letters or digits, like emoji-only replies, is only similar when identical.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    // Twitch rejects exact repeats, whatever they are made of
+    if a.trim() == b.trim() {
+        return 1.0;
+    }
+
+    let (a, b) = (normalize(a), normalize(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    if a == b {
+        return 1.0;
+    }
+    if a.len() < 2 || b.len() < 2 {
+        return 0.0;
+    }
+
+    let mut bigrams: HashMap<(char, char), usize> = HashMap::new();
+    for w in a.windows(2) {
+        *bigrams.entry((w[0], w[1])).or_default() += 1;
+    }
+
+    let mut shared = 0;
+    for w in b.windows(2) {
+        if let Some(n) = bigrams.get_mut(&(w[0], w[1])).filter(|n| **n > 0) {
+            *n -= 1;
+            shared += 1;
+        }
+    }
+
+    2.0 * shared as f64 / (a.len() - 1 + b.len() - 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_keeps_letters_and_digits() {
+        let normalized = |t: &str| normalize(t).into_iter().collect::<String>();
+        assert_eq!(normalized("GG, well played!! 🎉"), "ggwellplayed");
+        assert_eq!(normalized("こんにちは！"), "こんにちは");
+        assert_eq!(normalized("Round 2"), "round2");
+        assert_eq!(normalized("🔥🔥 ..."), "");
+    }
+
+    #[test]
+    fn similarity_cases() {
+        let cases = [
+            ("hello there", "hello there", 1.0),
+            ("Hello there!", "hello, there", 1.0),
+            ("  🔥🔥  ", "🔥🔥", 1.0),
+            ("!!!", "!!!", 1.0),
+            ("🔥🔥", "🎉🎉", 0.0),
+            ("🔥", "fire", 0.0),
+            ("a", "b", 0.0),
+            ("night", "nacht", 0.25),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(similarity(a, b), expected, "{:?} vs {:?}", a, b);
+        }
+        assert!(similarity("what a great play", "what a great play!!! lol") > 0.7);
+        assert!(similarity("what a great play", "time for lunch") < 0.3);
+    }
+}
@@ -0,0 +1,196 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::chat_model::{
+    core::{Message, ModelParams},
+    service::{guard::GuardOptions, types::CompletionError},
+};
+
+/// Extensions tried, in order, when an instruction is given without one
+pub const EXTENSIONS: [&str; 4] = ["json", "yaml", "yml", "md"];
+
+/// Instruction file contents, the same for every format
+#[derive(Debug, Clone, Default)]
+pub struct InstructionDoc {
+    pub messages: Vec<Message>,
+    pub params: ModelParams,
+    /// base instruction, relative to this file
+    pub extends: Option<String>,
+    /// snippet name, file relative to this file
+    pub include: BTreeMap<String, String>,
+    pub guard: Option<GuardOptions>,
+}
+
+/// Messages with parameters, the alternative to a bare list of messages
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FullDoc {
+    #[serde(default)]
+    params: ModelParams,
+    #[serde(default)]
+    messages: Vec<Message>,
+    extends: Option<String>,
+    #[serde(default)]
+    include: BTreeMap<String, String>,
+    guard: Option<GuardOptions>,
+}
+
+impl From<FullDoc> for InstructionDoc {
+    fn from(doc: FullDoc) -> Self {
+        Self {
+            messages: doc.messages,
+            params: doc.params,
+            extends: doc.extends,
+            include: doc.include,
+            guard: doc.guard,
+        }
+    }
+}
+
+/// Markdown front matter, model parameters next to `extends`, `include` and `guard`
+#[derive(Default, Deserialize)]
+struct FrontMatter {
+    #[serde(flatten)]
+    params: ModelParams,
+    extends: Option<String>,
+    #[serde(default)]
+    include: BTreeMap<String, String>,
+    guard: Option<GuardOptions>,
+    /// keys that are neither of the above, `deny_unknown_fields` doesn't work with `flatten`
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde_yml::Value>,
+}
+
+fn from_messages(messages: Vec<Message>) -> InstructionDoc {
+    InstructionDoc {
+        messages,
+        ..Default::default()
+    }
+}
+
+/// Parse an instruction file, the format is picked by extension.
+pub fn parse(path: &Path, contents: &str) -> Result<InstructionDoc, CompletionError> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match ext.as_str() {
+        "yaml" | "yml" => {
+            let value: serde_yml::Value = serde_yml::from_str(contents)?;
+            if value.is_sequence() {
+                Ok(from_messages(serde_yml::from_value(value)?))
+            } else {
+                Ok(serde_yml::from_value::<FullDoc>(value)?.into())
+            }
+        }
+        "md" => parse_markdown(contents),
+        _ => {
+            let value: serde_json::Value = serde_json::from_str(contents)?;
+            if value.is_array() {
+                Ok(from_messages(serde_json::from_value(value)?))
+            } else {
+                Ok(serde_json::from_value::<FullDoc>(value)?.into())
+            }
+        }
+    }
+}
+
+/// Split `---` delimited YAML front matter from the body.
+fn split_front_matter(contents: &str) -> (Option<&str>, &str) {
+    let rest = match contents.strip_prefix("---") {
+        Some(rest) if rest.starts_with(['\n', '\r']) => rest,
+        _ => return (None, contents),
+    };
+
+    match rest.find("\n---") {
+        Some(end) => {
+            let body = &rest[end + 4..];
+            let body = body.split_once('\n').map_or("", |(_, b)| b);
+            (Some(&rest[..end]), body)
+        }
+        None => (None, contents),
+    }
+}
+
+/// Front matter holds model parameters, `extends` and `include`,
+/// `## <role>` headings start messages.
+fn parse_markdown(contents: &str) -> Result<InstructionDoc, CompletionError> {
+    let (front_matter, body) = split_front_matter(contents);
+    let front: FrontMatter = match front_matter {
+        Some(yaml) if !yaml.trim().is_empty() => serde_yml::from_str(yaml)?,
+        _ => FrontMatter::default(),
+    };
+    if let Some(key) = front.unknown.keys().next() {
+        return Err(CompletionError::InvalidInstruction(format!(
+            "unknown field `{}` in front matter",
+            key
+        )));
+    }
+
+    let mut messages: Vec<Message> = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        if let Some(role) = line.strip_prefix("## ") {
+            messages.push(Message::new(role.trim().to_lowercase(), ""));
+            continue;
+        }
+
+        match messages.last_mut() {
+            Some(m) => {
+                m.content.push_str(line);
+                m.content.push('\n');
+            }
+            None if line.trim().is_empty() => {}
+            None => {
+                return Err(CompletionError::InvalidInstruction(format!(
+                    "line {} is outside of a \"## <role>\" section",
+                    i + 1
+                )))
+            }
+        }
+    }
+
+    for m in messages.iter_mut() {
+        m.content = m.content.trim().to_string();
+    }
+
+    Ok(InstructionDoc {
+        messages,
+        params: front.params,
+        extends: front.extends,
+        include: front.include,
+        guard: front.guard,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_front_matter() {
+        let doc = parse(
+            Path::new("a.md"),
+            "---\ntemperature: 0.5\nextends: base.md\n---\n## system\nhi\n",
+        )
+        .unwrap();
+        assert_eq!(doc.params.temperature, Some(0.5));
+        assert_eq!(doc.extends.as_deref(), Some("base.md"));
+        assert_eq!(doc.messages[0].content, "hi");
+
+        let err = parse(
+            Path::new("a.md"),
+            "---\nextend: base.md\n---\n## system\nhi\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("`extend`"), "{}", err);
+    }
+
+    #[test]
+    fn full_doc_rejects_unknown_fields() {
+        assert!(parse(Path::new("a.json"), r#"{"messages": [], "extend": "b"}"#).is_err());
+        assert!(parse(Path::new("a.yaml"), "messages: []\nextends: b.yaml").is_ok());
+    }
+}
@@ -0,0 +1,528 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+
+use crate::{
+    chat_model::{
+        core::{Message, ModelParams},
+        service::{
+            formats,
+            guard::{self, GuardOptions},
+            layers,
+            template::{self, HistoryEntry, TemplateContext},
+            types::CompletionError,
+        },
+    },
+    config::{channel::channel_key, reload, Account},
+    twitch::UserMsg,
+    viewers::ViewerMemory,
+};
+
+/// Template role replaced by one turn per history entry
+pub const HISTORY_ROLE: &str = "history";
+
+/// Roles an instruction file may use
+const VALID_ROLES: [&str; 4] = ["system", "user", "assistant", HISTORY_ROLE];
+
+/// Content of viewer turns when the `history` message has none
+const DEFAULT_TURN: &str = "{{ sender }}: {{ message }}";
+
+/// Parsed instruction file
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub path: PathBuf,
+    pub messages: Vec<Message>,
+    pub params: ModelParams,
+    /// `include:` snippets, name and template source
+    pub snippets: BTreeMap<String, String>,
+    /// prompt-injection defenses for the history
+    pub guard: GuardOptions,
+    /// every file the instruction was built from, with its modification time when loaded
+    pub sources: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Instruction {
+    /// True if none of the files changed since they were loaded.
+    fn is_current(&self) -> bool {
+        self.sources
+            .iter()
+            .all(|(path, loaded)| modified(path) == *loaded)
+    }
+}
+
+/// Path of the instruction file, `.json`, `.yaml`, `.yml` and `.md` are tried
+/// if the file doesn't exist as given.
+pub fn resolve_instruction_path(instruction: &str) -> Option<PathBuf> {
+    let p = Path::new(instruction);
+    if p.exists() {
+        return Some(p.to_path_buf());
+    }
+
+    formats::EXTENSIONS
+        .iter()
+        .map(|ext| PathBuf::from(format!("{}.{}", instruction, ext)))
+        .find(|candidate| candidate.exists())
+        .or_else(|| p.is_absolute().then(|| p.to_path_buf()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read and parse an instruction file along with its bases and snippets.
+pub fn load_instruction(instruction: &str) -> Result<Instruction, CompletionError> {
+    let path = resolve_instruction_path(instruction).ok_or_else(|| {
+        CompletionError::PathResolve(format!("unable to resolve path: {}", instruction))
+    })?;
+
+    let layered = layers::load(&path)?;
+
+    Ok(Instruction {
+        path,
+        messages: layered.messages,
+        params: layered.params,
+        snippets: layered.snippets,
+        guard: layered.guard,
+        sources: layered
+            .sources
+            .into_iter()
+            .map(|p| {
+                let m = modified(&p);
+                (p, m)
+            })
+            .collect(),
+    })
+}
+
+/// Build the template context from received chat, see instructions/README.md
+pub fn build_context(user_messages: &[UserMsg], account: &Account) -> TemplateContext {
+    let history = user_messages
+        .iter()
+        .map(|m| HistoryEntry {
+            sender: m.sender.clone(),
+            message: m.message.clone(),
+            own: m.sender == account.account_name,
+            flagged: false,
+            viewer: ViewerMemory::default(),
+        })
+        .collect();
+
+    TemplateContext {
+        account_name: account.account_name.clone(),
+        channel: account.channel.clone(),
+        history,
+        snippets: BTreeMap::new(),
+        deleted_messages: Vec::new(),
+        viewers: Vec::new(),
+        fence_history: false,
+    }
+}
+
+/// Context with made-up chat, for previews and validation
+pub fn sample_context(account: &Account) -> TemplateContext {
+    let samples = [
+        ("viewer_a", "hello!"),
+        ("viewer_b", "what game is this?"),
+        (account.account_name.as_str(), "welcome everyone"),
+        ("viewer_c", "lol nice play"),
+    ];
+
+    let chats: Vec<UserMsg> = samples
+        .iter()
+        .cycle()
+        .take(account.chat_history_size.max(samples.len()))
+        .map(|(sender, message)| UserMsg {
+            sender: sender.to_string(),
+            message: message.to_string(),
+            ..Default::default()
+        })
+        .collect();
+
+    build_context(&chats, account)
+}
+
+/// Render every message of the instruction, expanding `history` turns.
+pub fn render_messages(
+    instruction: &Instruction,
+    ctx: &TemplateContext,
+) -> Result<Vec<Message>, CompletionError> {
+    // snippets may render the history too, so guard it first
+    let mut guarded = ctx.clone();
+    guard::apply(&instruction.guard, &mut guarded.history);
+    if instruction.guard.fence {
+        // notes are summarized from viewer text
+        for viewer in guarded.viewers.iter_mut() {
+            viewer.notes = guard::escape(&viewer.notes);
+        }
+    }
+    guarded.fence_history = instruction.guard.fence;
+    let ctx = &with_snippets(instruction, &guarded)?;
+
+    let mut messages: Vec<Message> = Vec::new();
+    for (i, m) in instruction.messages.iter().enumerate() {
+        let template_error = |e: String| {
+            CompletionError::Template(format!(
+                "{} message {}: {}",
+                instruction.path.display(),
+                i,
+                e
+            ))
+        };
+
+        if m.role == HISTORY_ROLE {
+            let mut turns = expand_history(&m.content, ctx).map_err(template_error)?;
+            if ctx.fence_history {
+                for turn in turns.iter_mut().filter(|t| t.role == "user") {
+                    turn.content = format!(
+                        "{}\n{}\n{}",
+                        guard::FENCE_OPEN,
+                        turn.content,
+                        guard::FENCE_CLOSE
+                    );
+                }
+            }
+            messages.extend(turns);
+        } else {
+            let content = template::render(&m.content, ctx).map_err(template_error)?;
+            messages.push(Message {
+                content,
+                ..m.clone()
+            });
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Context with the instruction's snippets rendered into `snippets`
+fn with_snippets(
+    instruction: &Instruction,
+    ctx: &TemplateContext,
+) -> Result<TemplateContext, CompletionError> {
+    let mut snippets = BTreeMap::new();
+    for (name, source) in &instruction.snippets {
+        let rendered = template::render(source, ctx).map_err(|e| {
+            CompletionError::Template(format!(
+                "{} snippet {}: {}",
+                instruction.path.display(),
+                name,
+                e
+            ))
+        })?;
+        snippets.insert(name.clone(), rendered);
+    }
+
+    Ok(TemplateContext {
+        snippets,
+        ..ctx.clone()
+    })
+}
+
+/// Expand the history into turns: the bot's own messages become `assistant`
+/// turns, everyone else's `user` turns rendered with `turn_template`.
+fn expand_history(turn_template: &str, ctx: &TemplateContext) -> Result<Vec<Message>, String> {
+    let turn_template = match turn_template.trim() {
+        "" => DEFAULT_TURN,
+        t => t,
+    };
+
+    ctx.history
+        .iter()
+        .map(|entry| {
+            if entry.own {
+                Ok(Message::new("assistant", entry.message.clone()))
+            } else {
+                template::render_entry(turn_template, entry, ctx).map(|c| Message::new("user", c))
+            }
+        })
+        .collect()
+}
+
+/// Rough token count: ~4 ASCII characters per token, one token per other character
+pub fn estimate_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            let ascii = m.content.chars().filter(char::is_ascii).count();
+            let other = m.content.chars().count() - ascii;
+            ascii.div_ceil(4) + other + 4
+        })
+        .sum()
+}
+
+/// Problems that would make every generation of the account fail or misbehave
+pub fn validate(instruction: &Instruction, account: &Account) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    for (i, m) in instruction.messages.iter().enumerate() {
+        if !VALID_ROLES.contains(&m.role.as_str()) {
+            issues.push(format!(
+                "message {}: unknown role \"{}\", expected one of {}",
+                i,
+                m.role,
+                VALID_ROLES.join(", ")
+            ));
+        }
+    }
+
+    if instruction.messages.iter().all(|m| m.role == "system") {
+        issues.push("needs at least one non-system message".into());
+    }
+
+    // Rendering reports unknown variables and syntax errors
+    match render_messages(instruction, &sample_context(account)) {
+        Ok(messages) => {
+            let tokens = estimate_tokens(&messages);
+            if tokens > account.max_prompt_tokens {
+                issues.push(format!(
+                    "rendered prompt is ~{} tokens with sample history, limit is {}",
+                    tokens, account.max_prompt_tokens
+                ));
+            }
+        }
+        Err(err) => issues.push(err.to_string()),
+    }
+
+    if instruction.guard.fence {
+        issues.extend(unfenced_history(instruction, account));
+    }
+
+    issues
+}
+
+/// Messages rendering viewer text or notes outside `<chat_history>` although `guard.fence`
+/// is set, e.g. with `{% for m in history %}`
+fn unfenced_history(instruction: &Instruction, account: &Account) -> Vec<String> {
+    const PROBE: &str = "fenceprobe";
+    let mut ctx = sample_context(account);
+    let viewer = ViewerMemory {
+        login: "viewer_probe".into(),
+        display_name: "viewer_probe".into(),
+        notes: format!("{} notes", PROBE),
+        ..Default::default()
+    };
+    let probe = HistoryEntry {
+        sender: "viewer_probe".into(),
+        message: PROBE.into(),
+        own: false,
+        flagged: false,
+        viewer: viewer.clone(),
+    };
+    ctx.history.insert(0, probe.clone());
+    ctx.history.push(probe);
+    ctx.viewers.push(viewer);
+
+    let Ok(messages) = render_messages(instruction, &ctx) else {
+        return Vec::new();
+    };
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| {
+            m.content.match_indices(PROBE).any(|(at, _)| {
+                let before = &m.content[..at];
+                let open = before.rfind(guard::FENCE_OPEN);
+                open.is_none() || before.rfind(guard::FENCE_CLOSE) > open
+            })
+        })
+        .map(|(i, _)| {
+            format!(
+                "rendered message {} has viewer text or notes outside <chat_history> although guard.fence is set, \
+                 use `chat_lines`, a history turn or the `fence` filter",
+                i
+            )
+        })
+        .collect()
+}
+
+/// Load and validate, turning issues into an error
+pub fn load_validated(account: &Account, path: &str) -> Result<Instruction, CompletionError> {
+    let instruction = load_instruction(path)?;
+    let issues = validate(&instruction, account);
+    if !issues.is_empty() {
+        return Err(CompletionError::InvalidInstruction(format!(
+            "{}: {}",
+            instruction.path.display(),
+            issues.join("; ")
+        )));
+    }
+
+    Ok(instruction)
+}
+
+// account_name:channel|instruction, last valid instruction
+static INSTRUCTIONS: Lazy<Mutex<HashMap<String, Arc<Instruction>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn store_key(account: &Account, path: &str) -> String {
+    format!("{}|{}", channel_key(account), path)
+}
+
+/// Validated instruction `path` of the account, loaded on first use.
+///
+/// Later changes to the file are picked up by [`refresh`], from the file watcher or,
+/// when it isn't running, on every call.
+pub fn get_instruction(account: &Account, path: &str) -> Result<Arc<Instruction>, CompletionError> {
+    let key = store_key(account, path);
+    let cached = INSTRUCTIONS.lock().unwrap().get(&key).cloned();
+    match cached {
+        Some(cached) if reload::is_watching() => return Ok(cached),
+        Some(cached) => {
+            if let Err(err) = refresh(account, path) {
+                warn!("Keeping the last valid version of {}: {}", path, err);
+                return Ok(cached);
+            }
+        }
+        None => {
+            refresh(account, path)?;
+        }
+    }
+
+    INSTRUCTIONS
+        .lock()
+        .unwrap()
+        .get(&key)
+        .cloned()
+        .ok_or_else(|| CompletionError::PathResolve(path.to_string()))
+}
+
+/// Reload the instruction if the file or one it extends or includes changed.
+///
+/// An invalid file is rejected and the last valid version stays in use.
+/// Returns true if a new version was swapped in.
+pub fn refresh(account: &Account, path: &str) -> Result<bool, CompletionError> {
+    let key = store_key(account, path);
+    if let Some(cached) = INSTRUCTIONS.lock().unwrap().get(&key) {
+        let unchanged =
+            resolve_instruction_path(path).is_some_and(|p| p == cached.path && cached.is_current());
+        if unchanged {
+            return Ok(false);
+        }
+    }
+
+    let instruction = Arc::new(load_validated(account, path)?);
+    info!(
+        "Loaded instruction {} for {}",
+        instruction.path.display(),
+        channel_key(account)
+    );
+    INSTRUCTIONS.lock().unwrap().insert(key, instruction);
+
+    Ok(true)
+}
+
+/// True if every instruction of the account is in use from its current path.
+pub fn is_loaded(account: &Account) -> bool {
+    let store = INSTRUCTIONS.lock().unwrap();
+    account.instructions().into_iter().all(|path| {
+        let resolved = resolve_instruction_path(path);
+        store
+            .get(&store_key(account, path))
+            .is_some_and(|cached| Some(&cached.path) == resolved.as_ref())
+    })
+}
+
+/// Every file the loaded instructions were built from
+pub fn sources() -> Vec<PathBuf> {
+    INSTRUCTIONS
+        .lock()
+        .unwrap()
+        .values()
+        .flat_map(|i| i.sources.iter().map(|(p, _)| p.clone()))
+        .collect()
+}
+
+/// Validate without swapping anything in, e.g. before accepting a new config.
+pub fn check(account: &Account) -> Result<(), CompletionError> {
+    for path in account.instructions() {
+        load_validated(account, path)?;
+    }
+
+    Ok(())
+}
+
+/// Drop instructions of accounts and variants that no longer exist.
+pub fn prune(accounts: &[Account]) {
+    let keys: Vec<String> = accounts
+        .iter()
+        .flat_map(|a| a.instructions().into_iter().map(|p| store_key(a, p)))
+        .collect();
+    INSTRUCTIONS.lock().unwrap().retain(|k, _| keys.contains(k));
+}
+
+/// Load every account's instructions, returns false if any is invalid.
+pub fn load_all(accounts: &[Account]) -> bool {
+    let mut ok = true;
+    for account in accounts {
+        for path in account.instructions() {
+            if let Err(err) = get_instruction(account, path) {
+                error!("{}: {}", account.account_name, err);
+                ok = false;
+            }
+        }
+    }
+
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account() -> Account {
+        serde_yml::from_str(
+            "oauth: x\naccount_name: chatbot\nchannel: channel\ninstruction: x\n\
+             gpt_model: mock\noperating_mode: ALWAYS\ninterval: 1\ntimeout: 1\n\
+             chat_history_size: 4",
+        )
+        .unwrap()
+    }
+
+    fn fenced(system: &str, user: &str) -> Instruction {
+        Instruction {
+            path: PathBuf::from("test.json"),
+            messages: vec![Message::new("system", system), Message::new("user", user)],
+            params: ModelParams::default(),
+            snippets: BTreeMap::new(),
+            guard: GuardOptions {
+                fence: true,
+                ..Default::default()
+            },
+            sources: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn unfenced_history_probe() {
+        let account = account();
+        let cases = [
+            ("{{ history | chat_lines }}", true),
+            ("{history}", true),
+            (
+                "{% filter fence %}{% for m in history %}{{ m.message }}\n{% endfor %}{% endfilter %}",
+                true,
+            ),
+            ("{% for m in history %}{{ m.message }}\n{% endfor %}", false),
+            ("last: {{ (history | last).message }}", false),
+            (
+                "{% for v in viewers %}{{ v.notes }}{% endfor %}\n{{ history | chat_lines }}",
+                false,
+            ),
+            (
+                "{% filter fence %}{% for v in viewers %}{{ v.notes }}{% endfor %}{% endfilter %}",
+                true,
+            ),
+        ];
+        for (user, ok) in cases {
+            let issues = unfenced_history(&fenced("You are a bot.", user), &account);
+            assert_eq!(issues.is_empty(), ok, "{:?}: {:?}", user, issues);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::chat_model::{
+    core::{Message, ModelParams},
+    service::{
+        formats, guard::GuardOptions, instruction::resolve_instruction_path, types::CompletionError,
+    },
+};
+
+/// Deepest `extends:` chain accepted
+const MAX_DEPTH: usize = 8;
+
+/// Instruction with its `extends:` bases and `include:` snippets applied
+#[derive(Debug, Clone, Default)]
+pub struct Layered {
+    pub messages: Vec<Message>,
+    pub params: ModelParams,
+    /// snippet name, template source
+    pub snippets: BTreeMap<String, String>,
+    pub guard: GuardOptions,
+    /// every file read, the instruction itself first
+    pub sources: Vec<PathBuf>,
+}
+
+/// Load an instruction file and everything it extends or includes.
+///
+/// Paths in `extends:` and `include:` are relative to the file naming them.
+pub fn load(path: &Path) -> Result<Layered, CompletionError> {
+    load_layer(path, &mut Vec::new())
+}
+
+fn load_layer(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Layered, CompletionError> {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if chain.contains(&canonical) {
+        return Err(CompletionError::InvalidInstruction(format!(
+            "{} extends itself",
+            path.display()
+        )));
+    }
+    if chain.len() >= MAX_DEPTH {
+        return Err(CompletionError::InvalidInstruction(format!(
+            "{}: more than {} levels of extends",
+            path.display(),
+            MAX_DEPTH
+        )));
+    }
+
+    let contents = fs::read_to_string(path)
+        .map_err(|e| CompletionError::PathResolve(format!("{}: {}", path.display(), e)))?;
+    let doc = formats::parse(path, &contents)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut layered = match &doc.extends {
+        Some(base) => {
+            chain.push(canonical);
+            let base = load_layer(&relative_to(dir, base, true)?, chain)?;
+            chain.pop();
+            base
+        }
+        None => Layered::default(),
+    };
+    layered.sources.insert(0, path.to_path_buf());
+
+    for (name, snippet) in &doc.include {
+        let snippet_path = relative_to(dir, snippet, false)?;
+        let source = fs::read_to_string(&snippet_path).map_err(|e| {
+            CompletionError::PathResolve(format!(
+                "include {}: {}: {}",
+                name,
+                snippet_path.display(),
+                e
+            ))
+        })?;
+        layered
+            .snippets
+            .insert(name.clone(), source.trim_end().to_string());
+        layered.sources.push(snippet_path);
+    }
+
+    layered.params = doc.params.or(layered.params);
+    if let Some(guard) = doc.guard {
+        layered.guard = guard;
+    }
+    layered.messages = merge_messages(layered.messages, doc.messages);
+
+    Ok(layered)
+}
+
+/// `name` resolved against the directory of the including file.
+///
+/// Instructions may leave out the extension like in the config, snippets may not.
+fn relative_to(dir: &Path, name: &str, instruction: bool) -> Result<PathBuf, CompletionError> {
+    let joined = dir.join(name);
+    let resolved = if instruction {
+        resolve_instruction_path(&joined.to_string_lossy())
+    } else {
+        Some(joined.clone())
+    };
+
+    resolved.ok_or_else(|| {
+        CompletionError::PathResolve(format!("unable to resolve path: {}", joined.display()))
+    })
+}
+
+/// The n-th message of a role replaces the base's n-th message of that role,
+/// messages without a counterpart are appended.
+fn merge_messages(mut base: Vec<Message>, layer: Vec<Message>) -> Vec<Message> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for message in layer {
+        let n = seen.entry(message.role.clone()).or_default();
+        let slot = base
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.role == message.role)
+            .nth(*n)
+            .map(|(i, _)| i);
+        *n += 1;
+
+        match slot {
+            Some(i) => base[i] = message,
+            None => base.push(message),
+        }
+    }
+
+    base
+}
@@ -1,2 +1,9 @@
 pub mod completion;
+pub mod dedup;
+pub mod formats;
+pub mod guard;
+pub mod instruction;
+pub mod layers;
+pub mod postprocess;
+pub mod template;
 pub mod types;
@@ -0,0 +1,367 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+/// Step applied to the generated text before it is sent.
+///
+/// Every step is a plain function of the text, see [`PostProcessor::apply`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PostProcessor {
+    /// `"text"`, `'text'`, `「text」` and similar wrappers
+    StripQuotes,
+    /// `username:`, `assistant:`, `AI:` at the start
+    StripRolePrefix,
+    /// keep the first non-empty line
+    FirstLine,
+    /// join all lines with a space
+    JoinLines,
+    /// runs of whitespace become a single space
+    CollapseWhitespace,
+    /// `**bold**`, `` `code` ``, `[text](url)`, headings and list markers
+    StripMarkdown,
+    /// `#words`
+    StripHashtags,
+    /// regex replacement, `$1` refers to groups
+    Replace {
+        pattern: Pattern,
+        #[serde(default)]
+        replacement: String,
+    },
+    StripEmoji,
+    /// emoji past the first N are removed
+    MaxEmoji(usize),
+    /// cut to N characters, at a word boundary when there is one
+    ClampLength(usize),
+}
+
+/// Regex compiled when the config is parsed, so a bad pattern rejects the config
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Regex::new(&source)
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// (opening, closing) quote pairs
+const QUOTES: [(char, char); 7] = [
+    ('"', '"'),
+    ('\'', '\''),
+    ('“', '”'),
+    ('‘', '’'),
+    ('「', '」'),
+    ('『', '』'),
+    ('`', '`'),
+];
+
+static MD_LINK_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").unwrap());
+// emphasis and inline code, the content is kept
+static MD_EMPHASIS_RES: Lazy<Vec<Regex>> = Lazy::new(|| {
+    [
+        r"\*\*(.+?)\*\*",
+        r"__(.+?)__",
+        r"~~(.+?)~~",
+        // not between word characters, so "2*3*4" stays
+        r"\B\*([^\s*](?:[^*]*?[^\s*])?)\*\B",
+        r"`+([^`]+)`+",
+    ]
+    .iter()
+    .map(|re| Regex::new(re).unwrap())
+    .collect()
+});
+static MD_LINE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^\s*(#{1,6}\s+|>+\s+|[-*+]\s+|\d+\.\s+)").unwrap());
+static HASHTAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(^|\s)#[^\s#]+").unwrap());
+
+impl PostProcessor {
+    /// Apply the step, `account_name` is the prefix [`PostProcessor::StripRolePrefix`] removes.
+    pub fn apply(&self, text: &str, account_name: &str) -> String {
+        match self {
+            Self::StripQuotes => strip_quotes(text),
+            Self::StripRolePrefix => strip_role_prefix(text, account_name),
+            Self::FirstLine => text
+                .lines()
+                .map(str::trim)
+                .find(|l| !l.is_empty())
+                .unwrap_or_default()
+                .to_string(),
+            Self::JoinLines => text
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            Self::CollapseWhitespace => text.split_whitespace().collect::<Vec<_>>().join(" "),
+            Self::StripMarkdown => {
+                let text = MD_LINK_RE.replace_all(text, "$1").into_owned();
+                // line markers only on several lines, "1. place lets go" is plain chat
+                let text = if text.lines().filter(|l| !l.trim().is_empty()).count() > 1 {
+                    MD_LINE_RE.replace_all(&text, "").into_owned()
+                } else {
+                    text
+                };
+                MD_EMPHASIS_RES
+                    .iter()
+                    .fold(text, |text, re| re.replace_all(&text, "$1").into_owned())
+            }
+            Self::StripHashtags => HASHTAG_RE.replace_all(text, "$1").trim().to_string(),
+            Self::Replace {
+                pattern,
+                replacement,
+            } => pattern
+                .0
+                .replace_all(text, replacement.as_str())
+                .into_owned(),
+            Self::StripEmoji => limit_emoji(text, 0),
+            Self::MaxEmoji(max) => limit_emoji(text, *max),
+            Self::ClampLength(max) => clamp_length(text, *max),
+        }
+    }
+}
+
+/// Run the chain in order, the result is trimmed.
+pub fn apply_all(chain: &[PostProcessor], text: &str, account_name: &str) -> String {
+    chain.iter().fold(text.trim().to_string(), |text, step| {
+        step.apply(&text, account_name).trim().to_string()
+    })
+}
+
+fn strip_quotes(text: &str) -> String {
+    let mut text = text.trim();
+    while let Some(inner) = QUOTES.iter().find_map(|(open, close)| {
+        text.strip_prefix(*open)
+            .and_then(|t| t.strip_suffix(*close))
+            .filter(|inner| !inner.contains(*open) && !inner.contains(*close))
+    }) {
+        text = inner.trim();
+    }
+
+    text.to_string()
+}
+
+fn strip_role_prefix(text: &str, account_name: &str) -> String {
+    let mut text = text.trim();
+    while let Some((head, rest)) = text.split_once([':', '：']) {
+        let head = head.trim().trim_start_matches('@').to_lowercase();
+        let is_role = ["assistant", "ai", "bot", "system"].contains(&head.as_str())
+            || head == account_name.to_lowercase();
+        if !is_role {
+            break;
+        }
+        text = rest.trim_start();
+    }
+
+    text.to_string()
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF // pictographs, emoticons, flags
+        | 0x2600..=0x27BF // symbols and dingbats
+        | 0x2B00..=0x2BFF // arrows and stars
+    )
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Keep the first `max` emoji.
+///
+/// Skin tones, variation selectors, joined sequences and flags count as one emoji.
+fn limit_emoji(text: &str, max: usize) -> String {
+    let mut seen = 0;
+    let mut dropping = false;
+    let mut joined = false;
+    let mut half_flag = false;
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\u{200D}' => joined = true,
+            '\u{FE0F}' | '\u{1F3FB}'..='\u{1F3FF}' => {}
+            c if is_regional_indicator(c) && half_flag => half_flag = false,
+            c if is_emoji(c) => {
+                if !joined {
+                    seen += 1;
+                    dropping = seen > max;
+                }
+                joined = false;
+                half_flag = is_regional_indicator(c);
+            }
+            _ => {
+                dropping = false;
+                joined = false;
+                half_flag = false;
+            }
+        }
+
+        if !dropping {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+fn clamp_length(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+
+    let cut: String = text.chars().take(max).collect();
+    // Avoid ending mid-word unless that leaves too little
+    match cut.rfind(char::is_whitespace) {
+        Some(i) if cut[..i].chars().count() >= max / 2 => cut[..i].trim_end().to_string(),
+        _ => cut,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(step: PostProcessor, cases: &[(&str, &str)]) {
+        for (input, expected) in cases {
+            assert_eq!(
+                step.apply(input, "chatbot"),
+                *expected,
+                "{:?} on {:?}",
+                step,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn strip_quotes() {
+        check(
+            PostProcessor::StripQuotes,
+            &[
+                ("\"hello\"", "hello"),
+                ("'hi there'", "hi there"),
+                ("「こんにちは」", "こんにちは"),
+                ("\"'nested'\"", "nested"),
+                ("\"a\" and \"b\"", "\"a\" and \"b\""),
+                ("it's fine", "it's fine"),
+                ("\"unbalanced", "\"unbalanced"),
+            ],
+        );
+    }
+
+    #[test]
+    fn strip_role_prefix() {
+        check(
+            PostProcessor::StripRolePrefix,
+            &[
+                ("assistant: hi", "hi"),
+                ("AI: hi", "hi"),
+                ("@chatbot: hi", "hi"),
+                ("Chatbot：hi", "hi"),
+                ("bot: assistant: hi", "hi"),
+                ("viewer: hi", "viewer: hi"),
+                ("note: it works", "note: it works"),
+            ],
+        );
+    }
+
+    #[test]
+    fn lines_and_whitespace() {
+        check(
+            PostProcessor::FirstLine,
+            &[("\n  first \nsecond", "first"), ("", "")],
+        );
+        check(PostProcessor::JoinLines, &[("a\n\n b \nc", "a b c")]);
+        check(PostProcessor::CollapseWhitespace, &[("a  b\t\nc", "a b c")]);
+    }
+
+    #[test]
+    fn strip_markdown() {
+        check(
+            PostProcessor::StripMarkdown,
+            &[
+                ("**bold** and *it*", "bold and it"),
+                ("__under__ ~~gone~~ `code`", "under gone code"),
+                ("see [docs](https://x.y) ![img](a.png)", "see docs img"),
+                ("*waves* hi *grins*", "waves hi grins"),
+                ("2*3*4 is 24", "2*3*4 is 24"),
+                ("5 * 3 * 2", "5 * 3 * 2"),
+                ("1. place lets go", "1. place lets go"),
+                ("> quoting chat", "> quoting chat"),
+                ("- nice", "- nice"),
+                ("# title\n- one\n2. two\n> three", "title\none\ntwo\nthree"),
+            ],
+        );
+    }
+
+    #[test]
+    fn strip_hashtags() {
+        check(
+            PostProcessor::StripHashtags,
+            &[
+                ("gg #win #hype", "gg"),
+                ("#first word", "word"),
+                ("c# and f#", "c# and f#"),
+            ],
+        );
+    }
+
+    #[test]
+    fn replace() {
+        let step = PostProcessor::Replace {
+            pattern: Pattern(Regex::new(r"(\w+)@twitch").unwrap()),
+            replacement: "@$1".into(),
+        };
+        check(step, &[("hi user@twitch", "hi @user")]);
+    }
+
+    #[test]
+    fn limit_emoji() {
+        check(
+            PostProcessor::MaxEmoji(1),
+            &[
+                ("hi 😀😀😀", "hi 😀"),
+                ("👍🏽👍🏽 ok", "👍🏽 ok"),
+                ("👨‍👩‍👧 family 😀", "👨‍👩‍👧 family "),
+                ("🇯🇵🇺🇸", "🇯🇵"),
+                ("❤️❤️", "❤️"),
+                ("no emoji", "no emoji"),
+            ],
+        );
+        check(
+            PostProcessor::StripEmoji,
+            &[("gg 🎉 wp 👍🏽", "gg  wp "), ("👨‍👩‍👧", "")],
+        );
+    }
+
+    #[test]
+    fn clamp_length() {
+        check(
+            PostProcessor::ClampLength(10),
+            &[
+                ("short", "short"),
+                ("hello there world", "hello"),
+                ("abcdefghijklmnop", "abcdefghij"),
+                ("a bcdefghijklmnop", "a bcdefghi"),
+                ("ああああああああああああ", "ああああああああああ"),
+            ],
+        );
+    }
+
+    #[test]
+    fn apply_all_runs_in_order() {
+        let chain = [
+            PostProcessor::StripRolePrefix,
+            PostProcessor::StripQuotes,
+            PostProcessor::ClampLength(5),
+        ];
+        assert_eq!(
+            apply_all(&chain, " chatbot: \"hello world\" ", "chatbot"),
+            "hello"
+        );
+    }
+}
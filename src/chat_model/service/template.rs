@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use minijinja::{context, Environment, State, UndefinedBehavior, Value};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Serialize;
+
+use crate::{
+    chat_model::service::guard::{FENCE_CLOSE, FENCE_OPEN},
+    viewers::ViewerMemory,
+};
+
+/// Chat line exposed to templates
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub sender: String,
+    pub message: String,
+    /// sent by the bot itself
+    pub own: bool,
+    /// looks like a prompt-injection attempt, see the `guard` instruction option
+    pub flagged: bool,
+    /// what the bot remembers about the sender, empty for unknown senders
+    pub viewer: ViewerMemory,
+}
+
+/// Variables available to instruction templates, see instructions/README.md
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateContext {
+    pub account_name: String,
+    pub channel: String,
+    pub history: Vec<HistoryEntry>,
+    /// rendered `include:` snippets by name
+    pub snippets: BTreeMap<String, String>,
+    /// the bot's messages moderators deleted in this channel, oldest first
+    pub deleted_messages: Vec<String>,
+    /// memory of the viewers in the history, in order of appearance
+    pub viewers: Vec<ViewerMemory>,
+    /// `chat_lines` and `fence` wrap their output in `<chat_history>` tags
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub fence_history: bool,
+}
+
+static ENV: Lazy<Environment<'static>> = Lazy::new(|| {
+    let mut env = Environment::new();
+    // unknown variables are errors instead of empty strings
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.add_filter("truncate", truncate);
+    env.add_filter("chat_lines", chat_lines);
+    env.add_filter("fence", fence);
+    env
+});
+
+// {{ ... }} blocks, or a pre-engine placeholder like {history}
+static LEGACY_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{\{.*?\}\}|\{%.*?%\}|\{(?P<name>history|account_name|channel|viewer\.notes)\}")
+        .unwrap()
+});
+
+/// Render a single message template.
+///
+/// Errors carry the line and a short excerpt of the template.
+pub fn render(source: &str, ctx: &TemplateContext) -> Result<String, String> {
+    render_value(source, Value::from_serialize(ctx))
+}
+
+/// Render the template of a `history` turn for one entry.
+///
+/// `sender`, `message`, `own` and `viewer` are available next to the usual context.
+pub fn render_entry(
+    source: &str,
+    entry: &HistoryEntry,
+    ctx: &TemplateContext,
+) -> Result<String, String> {
+    let value = context! {
+        sender => entry.sender,
+        message => entry.message,
+        own => entry.own,
+        viewer => entry.viewer,
+        ..Value::from_serialize(ctx)
+    };
+    render_value(source, value)
+}
+
+fn render_value(source: &str, value: Value) -> Result<String, String> {
+    let source = upgrade_legacy_placeholders(source);
+    let tmpl = ENV
+        .template_from_str(&source)
+        .map_err(|err| format!("{:#}", err))?;
+
+    // Name the culprit instead of a bare "undefined value"
+    let mut unknown: Vec<String> = tmpl
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|name| value.get_attr(name).is_ok_and(|v| v.is_undefined()))
+        .filter(|name| !ENV.globals().any(|(global, _)| global == name))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(format!(
+            "unknown variable(s) {}, available: {}",
+            unknown.join(", "),
+            context_keys(&value).join(", ")
+        ));
+    }
+
+    tmpl.render(value).map_err(|err| format!("{:#}", err))
+}
+
+fn context_keys(value: &Value) -> Vec<String> {
+    value
+        .try_iter()
+        .map(|keys| keys.map(|k| k.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Rewrite `{history}`, `{account_name}`, `{channel}` and `{viewer.notes}` to engine syntax.
+///
+/// `{history}` keeps its old meaning, messages joined by commas, fenced like `chat_lines`.
+fn upgrade_legacy_placeholders(source: &str) -> String {
+    LEGACY_RE
+        .replace_all(source, |caps: &Captures| match caps.name("name") {
+            Some(name) if name.as_str() == "history" => {
+                r#"{{ history | rejectattr("own") | map(attribute="message") | join(",") | fence }}"#
+                    .to_string()
+            }
+            Some(name) => format!("{{{{ {} }}}}", name.as_str()),
+            None => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// `{{ text | truncate(20) }}`, cuts to `length` characters and appends `end` ("..." by default)
+fn truncate(value: String, length: Option<usize>, end: Option<String>) -> String {
+    let length = length.unwrap_or(255);
+    if value.chars().count() <= length {
+        return value;
+    }
+
+    let end = end.unwrap_or_else(|| "...".into());
+    let keep = length.saturating_sub(end.chars().count());
+    value.chars().take(keep).chain(end.chars()).collect()
+}
+
+/// `{{ history | chat_lines }}`, one `sender: message` per line
+fn chat_lines(state: &State, history: Value) -> Result<String, minijinja::Error> {
+    let mut lines = Vec::new();
+    for entry in history.try_iter()? {
+        lines.push(format!(
+            "{}: {}",
+            entry.get_attr("sender")?,
+            entry.get_attr("message")?
+        ));
+    }
+
+    Ok(fence(state, lines.join("\n")))
+}
+
+/// `{{ text | fence }}` or `{% filter fence %}...{% endfilter %}`, wraps the text in
+/// `<chat_history>` tags when the instruction's guard has `fence` set
+fn fence(state: &State, text: String) -> String {
+    if state.lookup("fence_history").is_some_and(|v| v.is_true()) {
+        format!("{}\n{}\n{}", FENCE_OPEN, text, FENCE_CLOSE)
+    } else {
+        text
+    }
+}
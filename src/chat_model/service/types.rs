@@ -9,6 +9,18 @@ pub enum CompletionError {
     Io(#[from] std::io::Error),
     #[error("Json error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("YAML error: {0}")]
+    Yaml(#[from] serde_yml::Error),
     #[error("Chat model error: {0}")]
     ChatModel(#[from] ChatModelError),
+    #[error("Invalid instruction {0}")]
+    InvalidInstruction(String),
+    #[error("Template error: {0}")]
+    Template(String),
+    #[error("No final answer after {0} tool rounds")]
+    ToolRounds(usize),
+    #[error("Nothing left to send after post-processing {0:?}")]
+    EmptyAfterPostProcess(String),
+    #[error("Still repeating {similar:?} after regenerating: {text:?}")]
+    Repeated { text: String, similar: String },
 }
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde_json::json;
+
+use crate::{
+    chat_model::{
+        core::ToolDefinition,
+        tools::{ToolError, ToolHandler, ToolRegistry},
+    },
+    config::Account,
+    twitch::{
+        utils::{get_stream_info, StreamInfo},
+        UserMsg,
+    },
+};
+
+/// Build the registry of tools enabled for the account.
+pub fn registry(account: &Account, chats: &[UserMsg], client: &reqwest::Client) -> ToolRegistry {
+    let mut registry = ToolRegistry::new();
+
+    for name in &account.tools {
+        let stream = |field| StreamField {
+            field,
+            channel: account.channel.clone(),
+            client: client.clone(),
+        };
+
+        match name.as_str() {
+            "get_stream_title" => registry.register(stream(Field::Title)),
+            "get_current_game" => registry.register(stream(Field::Game)),
+            "get_uptime" => registry.register(stream(Field::Uptime)),
+            "get_recent_chatters" => registry.register(RecentChatters::new(chats)),
+            "lookup_command" => registry.register(LookupCommand {
+                commands: account.commands.clone(),
+            }),
+            _ => warn!("Unknown tool {} for {}", name, account.account_name),
+        }
+    }
+
+    registry
+}
+
+fn no_parameters() -> serde_json::Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Title,
+    Game,
+    Uptime,
+}
+
+/// Title, game or uptime of the channel
+struct StreamField {
+    field: Field,
+    channel: String,
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl ToolHandler for StreamField {
+    fn definition(&self) -> ToolDefinition {
+        let (name, description) = match self.field {
+            Field::Title => ("get_stream_title", "Current title of the stream"),
+            Field::Game => ("get_current_game", "Game or category being streamed"),
+            Field::Uptime => ("get_uptime", "How long the stream has been live"),
+        };
+
+        ToolDefinition {
+            name: name.into(),
+            description: description.into(),
+            parameters: no_parameters(),
+        }
+    }
+
+    async fn call(&self, _args: serde_json::Value) -> Result<String, ToolError> {
+        let info: StreamInfo = get_stream_info(&self.channel, &self.client)
+            .await
+            .ok_or_else(|| ToolError::Unavailable("Twitch API request failed".into()))?;
+
+        let unknown = || "unknown".to_string();
+        Ok(match self.field {
+            Field::Title => info.title.unwrap_or_else(unknown),
+            Field::Game => info.game.unwrap_or_else(unknown),
+            Field::Uptime => match info.started_at {
+                Some(started_at) => format_uptime(&started_at)?,
+                None => "offline".into(),
+            },
+        })
+    }
+}
+
+fn format_uptime(started_at: &str) -> Result<String, ToolError> {
+    let started = DateTime::parse_from_rfc3339(started_at)
+        .map_err(|e| ToolError::Unavailable(format!("bad createdAt: {}", e)))?;
+    let minutes = (Utc::now() - started.with_timezone(&Utc))
+        .num_minutes()
+        .max(0);
+
+    Ok(format!("{}h {}m", minutes / 60, minutes % 60))
+}
+
+/// Users who spoke in the received history
+struct RecentChatters {
+    chatters: Vec<String>,
+}
+
+impl RecentChatters {
+    fn new(chats: &[UserMsg]) -> Self {
+        let mut chatters: Vec<String> = Vec::new();
+        for chat in chats {
+            if !chatters.contains(&chat.sender) {
+                chatters.push(chat.sender.clone());
+            }
+        }
+
+        Self { chatters }
+    }
+}
+
+#[async_trait]
+impl ToolHandler for RecentChatters {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "get_recent_chatters".into(),
+            description: "Names of viewers who chatted recently".into(),
+            parameters: no_parameters(),
+        }
+    }
+
+    async fn call(&self, _args: serde_json::Value) -> Result<String, ToolError> {
+        Ok(self.chatters.join(", "))
+    }
+}
+
+/// Channel chat commands from the account config
+struct LookupCommand {
+    commands: HashMap<String, String>,
+}
+
+#[async_trait]
+impl ToolHandler for LookupCommand {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "lookup_command".into(),
+            description: "Look up what a chat command (e.g. !discord) does".into(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "command name, e.g. !discord" }
+                },
+                "required": ["command"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: serde_json::Value) -> Result<String, ToolError> {
+        let command = args
+            .get("command")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let key = format!("!{}", command.trim_start_matches('!'));
+
+        Ok(self
+            .commands
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| format!("{} is not a known command", key)))
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::chat_model::core::{ToolCall, ToolDefinition};
+
+pub mod builtin;
+
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("Unknown tool: {0}")]
+    Unknown(String),
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(#[from] serde_json::Error),
+    #[error("Tool unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Rust function the model can call
+#[async_trait]
+pub trait ToolHandler: Send + Sync {
+    fn definition(&self) -> ToolDefinition;
+    async fn call(&self, args: serde_json::Value) -> Result<String, ToolError>;
+}
+
+/// Tools offered to the model, by name so definitions keep a stable order
+#[derive(Default)]
+pub struct ToolRegistry {
+    handlers: BTreeMap<String, Box<dyn ToolHandler>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<H: ToolHandler + 'static>(&mut self, handler: H) {
+        let name = handler.definition().name;
+        self.handlers.insert(name, Box::new(handler));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.handlers.values().map(|h| h.definition()).collect()
+    }
+
+    /// Run a tool call. Failures are reported back to the model as text.
+    pub async fn call(&self, call: &ToolCall) -> String {
+        let result = match self.handlers.get(&call.name) {
+            Some(handler) => match serde_json::from_str(&call.arguments) {
+                Ok(args) => handler.call(args).await,
+                Err(err) => Err(err.into()),
+            },
+            None => Err(ToolError::Unknown(call.name.clone())),
+        };
+
+        match result {
+            Ok(output) => {
+                debug!("tool {} -> {}", call.name, output);
+                output
+            }
+            Err(err) => {
+                warn!("tool {} failed: {}", call.name, err);
+                format!("error: {}", err)
+            }
+        }
+    }
+}
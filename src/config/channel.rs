@@ -14,12 +14,22 @@ pub static CHANNELS: Lazy<Mutex<HashMap<String, Instant>>> =
 
 pub fn init_channels() {
     let mut ch = CHANNELS.lock().unwrap();
-    for acc in &CONFIG.accounts {
+    for acc in &CONFIG.get().accounts {
         ch.insert(channel_key(acc), Instant::now());
     }
 }
 
-fn channel_key(account: &Account) -> String {
+/// Schedule added accounts right away and forget removed ones.
+pub fn sync_channels(accounts: &[Account]) {
+    let keys: Vec<String> = accounts.iter().map(channel_key).collect();
+    let mut ch = CHANNELS.lock().unwrap();
+    ch.retain(|k, _| keys.contains(k));
+    for key in keys {
+        ch.entry(key).or_insert_with(Instant::now);
+    }
+}
+
+pub fn channel_key(account: &Account) -> String {
     format!("{}:{}", account.account_name, account.channel)
 }
 
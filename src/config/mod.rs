@@ -1,7 +1,20 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
 use once_cell::sync::Lazy;
 use serde::Deserialize;
+use thiserror::Error;
+
+use crate::chat_model::{
+    middleware::cassette::CassetteConfig,
+    providers::mock::MockConfig,
+    service::postprocess::{Pattern, PostProcessor},
+};
 
 pub mod channel;
+pub mod reload;
 pub mod utils;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -12,6 +25,27 @@ pub struct Config {
     pub accounts: Vec<Account>,
     #[serde(rename = "OpenAI")]
     pub openai: OpenAIConfig,
+    #[serde(rename = "Http", default)]
+    pub http: HttpConfig,
+    #[serde(rename = "Usage", default)]
+    pub usage: UsageConfig,
+    #[serde(rename = "Cache", default)]
+    pub cache: CacheConfig,
+    #[serde(rename = "Experiments", default)]
+    pub experiments: ExperimentsConfig,
+    #[serde(rename = "Consent", default)]
+    pub consent: ConsentConfig,
+    #[serde(rename = "Sanctions", default)]
+    pub sanctions: SanctionsConfig,
+    #[serde(rename = "KillSwitch", default)]
+    pub kill_switch: KillSwitchConfig,
+    #[serde(rename = "Store", default)]
+    pub store: StoreConfig,
+    #[serde(rename = "Viewers", default)]
+    pub viewers: ViewersConfig,
+    /// answers of the MOCK provider
+    #[serde(rename = "Mock", default)]
+    pub mock: MockConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -24,6 +58,217 @@ pub struct OpenAIConfig {
     pub api_key: String,
 }
 
+/// Outbound HTTP settings, timeouts in seconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout: u64,
+    pub read_timeout: u64,
+    pub user_agent: String,
+    pub pool_idle_timeout: u64,
+    pub pool_max_idle_per_host: usize,
+    pub proxy: Option<String>,
+    /// path to a PEM file with extra root certificates
+    pub ca_bundle: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 10,
+            read_timeout: 60,
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).into(),
+            pool_idle_timeout: 90,
+            pool_max_idle_per_host: 8,
+            proxy: None,
+            ca_bundle: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    /// JSON file the token ledger is kept in
+    pub path: String,
+    /// model name -> price
+    pub prices: HashMap<String, ModelPrice>,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            path: "data/usage.json".into(),
+            prices: HashMap::new(),
+        }
+    }
+}
+
+/// Response cache for identical prompts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// seconds an answer stays valid
+    pub ttl: u64,
+    /// entries kept in memory
+    pub max_entries: usize,
+    /// optional directory for entries that survive restarts
+    pub path: Option<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl: 300,
+            max_entries: 256,
+            path: None,
+        }
+    }
+}
+
+/// A/B testing of instruction variants
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExperimentsConfig {
+    /// JSON file the trials are kept in
+    pub path: String,
+    /// seconds chat is watched after a message for replies, deletions and rejections
+    pub observe_seconds: u64,
+    /// oldest trials are dropped past this
+    pub max_trials: usize,
+}
+
+impl Default for ExperimentsConfig {
+    fn default() -> Self {
+        Self {
+            path: "data/experiments.json".into(),
+            observe_seconds: 30,
+            max_trials: 5000,
+        }
+    }
+}
+
+/// Channels that opted out with `!botoff`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConsentConfig {
+    /// JSON file the opt-outs are kept in
+    pub path: String,
+}
+
+impl Default for ConsentConfig {
+    fn default() -> Self {
+        Self {
+            path: "data/opt_out.json".into(),
+        }
+    }
+}
+
+/// Timeouts, bans and deleted messages of the bot accounts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SanctionsConfig {
+    /// JSON file the events are kept in
+    pub path: String,
+    /// deleted messages passed to templates as `deleted_messages`
+    pub negative_examples: usize,
+    /// oldest events are dropped past this, active bans are kept
+    pub max_events: usize,
+}
+
+impl Default for SanctionsConfig {
+    fn default() -> Self {
+        Self {
+            path: "data/sanctions.json".into(),
+            negative_examples: 5,
+            max_events: 1000,
+        }
+    }
+}
+
+/// SQLite database with chat, prompts and outcomes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub path: String,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            path: "data/chatbot.sqlite3".into(),
+        }
+    }
+}
+
+/// Long-term memory of viewers, kept in the store
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ViewersConfig {
+    /// off unless set, notes are personal data
+    pub enabled: bool,
+    /// new messages of a viewer before their notes are summarized again
+    pub summarize_after: u64,
+    /// model calls for notes per message cycle
+    pub max_summaries_per_cycle: usize,
+    pub max_notes_chars: usize,
+}
+
+impl Default for ViewersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            summarize_after: 10,
+            max_summaries_per_cycle: 1,
+            max_notes_chars: 300,
+        }
+    }
+}
+
+/// Stops all posting while the bot keeps reading chat
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KillSwitchConfig {
+    /// sentinel file stopping every account, `<file>.<account_name>` stops a single one
+    pub file: String,
+    /// Twitch logins allowed to use `!kill` and `!resume` in chat
+    pub owners: Vec<String>,
+    /// HTTP endpoint to flip the switch, off if not set
+    pub admin: Option<AdminConfig>,
+}
+
+impl Default for KillSwitchConfig {
+    fn default() -> Self {
+        Self {
+            file: "data/KILL".into(),
+            owners: Vec::new(),
+            admin: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConfig {
+    /// address to listen on
+    #[serde(default = "default_admin_bind")]
+    pub bind: String,
+    /// required as `Authorization: Bearer <token>`, the endpoint doesn't start without it
+    pub token: Option<String>,
+}
+
+fn default_admin_bind() -> String {
+    "127.0.0.1:8787".into()
+}
+
+/// USD per 1M tokens
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum OperatingMode {
     ALWAYS,
@@ -41,8 +286,269 @@ pub struct Account {
     pub operating_mode: OperatingMode,
     pub interval: usize,
     pub timeout: usize,
+    /// messages of others in the history, the bot's own are counted separately
     pub chat_history_size: usize,
+    /// new messages a cycle waits for before generating, `chat_history_size` if unset
+    #[serde(default)]
+    pub min_new_messages: Option<usize>,
+    /// stored chat older than this many seconds is left out of the history
+    #[serde(default = "default_history_window_secs")]
+    pub history_window_secs: u64,
     pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub provider: Provider,
+    #[serde(default)]
+    pub fallback_models: Vec<ModelTarget>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// generation stops once the text gets longer than this (Twitch allows 500)
+    #[serde(default = "default_max_message_length")]
+    pub max_message_length: usize,
+    /// instructions rendering to more (estimated) tokens are rejected
+    #[serde(default = "default_max_prompt_tokens")]
+    pub max_prompt_tokens: usize,
+    /// tools the model may call, see chat_model::tools::builtin
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// chat commands known to `lookup_command`, e.g. "!discord": "link to the discord"
+    #[serde(default)]
+    pub commands: HashMap<String, String>,
+    #[serde(default)]
+    pub budget: BudgetConfig,
+    /// record or replay model calls, for regression tests of prompts
+    pub cassette: Option<CassetteConfig>,
+    /// answer identical prompts from the response cache when `Cache` is enabled
+    #[serde(default = "default_true")]
+    pub use_cache: bool,
+    /// weighted instructions to compare, `instruction` is ignored when set
+    #[serde(default)]
+    pub variants: Vec<InstructionVariant>,
+    /// steps applied in order to the generated text before sending
+    #[serde(default)]
+    pub post_process: Vec<PostProcessor>,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    /// the broadcaster agreed to the bot posting in the channel, nothing is sent without it
+    #[serde(default)]
+    pub broadcaster_consent: bool,
+    pub disclosure: Option<DisclosureConfig>,
+    /// the account follows the channel long enough to post in followers-only mode
+    #[serde(default)]
+    pub follows_channel: bool,
+}
+
+impl Account {
+    /// New messages a cycle waits for
+    pub fn new_messages(&self) -> usize {
+        self.min_new_messages.unwrap_or(self.chat_history_size)
+    }
+
+    /// Instruction files the account generates with
+    pub fn instructions(&self) -> Vec<&str> {
+        if self.variants.is_empty() {
+            vec![self.instruction.as_str()]
+        } else {
+            self.variants
+                .iter()
+                .map(|v| v.instruction.as_str())
+                .collect()
+        }
+    }
+}
+
+/// Instruction picked for a generation with probability weight / sum of weights
+#[derive(Debug, Clone, Deserialize)]
+pub struct InstructionVariant {
+    pub name: String,
+    pub instruction: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Marks messages as written by an AI
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DisclosureConfig {
+    /// prepended to every message, e.g. "[AI] "
+    pub prefix: String,
+    /// appended to every message, e.g. " (bot)"
+    pub suffix: String,
+    /// separate message sent at most every `notice_interval` seconds
+    pub notice: Option<String>,
+    pub notice_interval: u64,
+}
+
+impl Default for DisclosureConfig {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            suffix: String::new(),
+            notice: None,
+            notice_interval: 3600,
+        }
+    }
+}
+
+/// Suppression of messages repeating what was recently sent to the channel
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    pub enabled: bool,
+    /// seconds sent messages are compared against
+    pub window_secs: u64,
+    /// 0.0 to 1.0, generations at least this similar count as repeats
+    pub threshold: f64,
+    /// regenerations with a "don't repeat" hint before the cycle is skipped
+    pub retries: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_secs: 600,
+            threshold: 0.85,
+            retries: 1,
+        }
+    }
+}
+
+/// Checks generated messages and incoming chat have to pass
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SafetyConfig {
+    /// case-insensitive, whole words for latin text
+    pub blocked_words: Vec<String>,
+    pub blocked_patterns: Vec<Pattern>,
+    pub links: LinkPolicy,
+    /// domains (and their subdomains) allowed with `links: ALLOWLIST`
+    pub allowed_domains: Vec<String>,
+    pub moderation: Option<ModerationConfig>,
+    pub on_block: BlockAction,
+    /// regenerations with `on_block: REGENERATE` before the cycle is skipped
+    pub retries: usize,
+    /// leave viewer messages that fail the checks out of the history
+    pub filter_history: bool,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            blocked_words: Vec::new(),
+            blocked_patterns: Vec::new(),
+            links: LinkPolicy::default(),
+            allowed_domains: Vec::new(),
+            moderation: None,
+            on_block: BlockAction::default(),
+            retries: 1,
+            filter_history: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Default)]
+pub enum LinkPolicy {
+    #[default]
+    ALLOW,
+    BLOCK,
+    /// only links to `allowed_domains`
+    ALLOWLIST,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Default)]
+pub enum BlockAction {
+    #[default]
+    REGENERATE,
+    DROP,
+}
+
+/// OpenAI-compatible `/moderations` endpoint, a local classifier can serve the same API
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModerationConfig {
+    #[serde(default = "default_moderation_url")]
+    pub url: String,
+    #[serde(default = "default_moderation_model")]
+    pub model: String,
+    /// defaults to the OpenAI key
+    pub api_key: Option<String>,
+    /// also run incoming chat through the endpoint
+    #[serde(default)]
+    pub check_history: bool,
+}
+
+fn default_moderation_url() -> String {
+    "https://api.openai.com/v1/moderations".into()
+}
+
+fn default_moderation_model() -> String {
+    "omni-moderation-latest".into()
+}
+
+/// Spending limits in USD, generation pauses once reached
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct BudgetConfig {
+    pub daily_usd: Option<f64>,
+    pub monthly_usd: Option<f64>,
+}
+
+fn default_max_message_length() -> usize {
+    500
+}
+
+fn default_max_prompt_tokens() -> usize {
+    4000
+}
+
+fn default_history_window_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Default)]
+pub enum Provider {
+    #[default]
+    OPENAI,
+    /// canned answers from the `Mock` section, no network
+    MOCK,
+}
+
+/// Provider/model pair used as a fallback
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelTarget {
+    #[serde(default)]
+    pub provider: Provider,
+    pub model: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// retries per model, not counting the first attempt
+    pub max_retries: usize,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// per-request timeout in seconds
+    pub request_timeout: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            request_timeout: 60,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -52,16 +558,42 @@ pub struct ProxyConfig {
     pub password: Option<String>,
 }
 
-pub fn load_config() -> Config {
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Failed to parse YAML in {0}: {1}")]
+    Parse(String, serde_yml::Error),
+}
+
+pub fn try_load_config() -> Result<Config, ConfigError> {
     let path = get_config_path();
-    let contents = std::fs::read_to_string(&path)
-        .unwrap_or_else(|e| panic!("Failed to read config file {}: {}", path, e));
-    serde_yml::from_str::<Config>(&contents)
-        .unwrap_or_else(|e| panic!("Failed to parse YAML in {}: {}", path, e))
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+    serde_yml::from_str::<Config>(&contents).map_err(|e| ConfigError::Parse(path, e))
 }
 
-fn get_config_path() -> String {
+pub fn load_config() -> Config {
+    try_load_config().unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn get_config_path() -> String {
     std::env::var("CONFIG_PATH").expect("CONFIG_PATH should be specified")
 }
 
-pub static CONFIG: Lazy<Config> = Lazy::new(|| load_config());
+/// Current config, replaced as a whole on reload
+pub struct SharedConfig(RwLock<Arc<Config>>);
+
+impl SharedConfig {
+    /// Snapshot of the current config. Holders keep it alive across a reload.
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+pub static CONFIG: Lazy<SharedConfig> =
+    Lazy::new(|| SharedConfig(RwLock::new(Arc::new(load_config()))));
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{
+    chat_model::service::instruction,
+    config::{
+        channel::sync_channels, get_config_path, try_load_config, Config, ConfigError, CONFIG,
+    },
+};
+
+/// Quiet period after a change before reloading, editors often write several times
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// True once [`spawn_watcher`] is running
+pub fn is_watching() -> bool {
+    WATCHING.load(Ordering::Relaxed)
+}
+
+/// Parse the config, validate every account's instruction and swap it in.
+///
+/// Anything invalid is logged and the last good version stays in use.
+pub fn reload() {
+    match load_valid_config() {
+        Ok(config) => {
+            sync_channels(&config.accounts);
+            instruction::prune(&config.accounts);
+            CONFIG.replace(config);
+        }
+        Err(err) => error!("Keeping previous config: {}", err),
+    }
+
+    for account in &CONFIG.get().accounts {
+        for path in account.instructions() {
+            match instruction::refresh(account, path) {
+                Ok(true) => info!("Reloaded {} for {}", path, account.account_name),
+                Ok(false) => {}
+                Err(err) => error!(
+                    "Keeping previous {} for {}: {}",
+                    path, account.account_name, err
+                ),
+            }
+        }
+    }
+}
+
+fn load_valid_config() -> Result<Config, String> {
+    let config = try_load_config().map_err(|e: ConfigError| e.to_string())?;
+
+    // Accounts keeping their instruction file fall back to its last good
+    // version, only new or moved instructions have to be valid up front
+    for account in config
+        .accounts
+        .iter()
+        .filter(|a| !instruction::is_loaded(a))
+    {
+        instruction::check(account)
+            .map_err(|e| format!("account {}: {}", account.account_name, e))?;
+    }
+
+    Ok(config)
+}
+
+/// Directories holding the config, every instruction file and the files
+/// they extend or include.
+///
+/// Directories are watched rather than files, since editors tend to replace files.
+fn watched_dirs() -> HashSet<PathBuf> {
+    let dir_of = |p: &Path| {
+        p.parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    };
+
+    std::iter::once(dir_of(Path::new(&get_config_path())))
+        .chain(
+            CONFIG
+                .get()
+                .accounts
+                .iter()
+                .flat_map(|a| a.instructions())
+                .map(|p| dir_of(Path::new(p))),
+        )
+        .chain(instruction::sources().iter().map(|p| dir_of(p)))
+        .collect()
+}
+
+/// Watch the config and instruction files and reload on change.
+pub fn spawn_watcher() -> notify::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher: RecommendedWatcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if !event.kind.is_access() => {
+                let _ = tx.send(event.paths);
+            }
+            Ok(_) => {}
+            Err(err) => warn!("File watcher error: {}", err),
+        })?;
+
+    let mut dirs = watched_dirs();
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    tokio::spawn(async move {
+        while let Some(paths) = rx.recv().await {
+            // collapse bursts of events into one reload
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            debug!("Files changed: {:?}", paths);
+            reload();
+
+            // instructions may have moved to new directories
+            for dir in watched_dirs() {
+                if dirs.contains(&dir) {
+                    continue;
+                }
+                match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                    Ok(()) => {
+                        dirs.insert(dir);
+                    }
+                    Err(err) => warn!("Failed to watch {}: {}", dir.display(), err),
+                }
+            }
+        }
+    });
+    WATCHING.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
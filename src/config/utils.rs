@@ -1,10 +1,11 @@
 use crate::config::CONFIG;
 
 /// Get list of chatbot name
-pub fn get_account_names() -> Vec<&'static str> {
+pub fn get_account_names() -> Vec<String> {
     CONFIG
+        .get()
         .accounts
         .iter()
-        .map(|v| v.account_name.as_str())
+        .map(|v| v.account_name.clone())
         .collect::<Vec<_>>()
 }
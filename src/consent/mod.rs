@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{channel::channel_key, Account, ConsentConfig, CONFIG},
+    persist::{read_json, write_json},
+    twitch::UserMsg,
+};
+
+/// Silences every bot account in the channel
+pub const OFF_COMMAND: &str = "!botoff";
+/// Lifts `!botoff`
+pub const ON_COMMAND: &str = "!boton";
+
+/// Channel where the broadcaster or a moderator turned the bot off
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OptOut {
+    pub channel: String,
+    pub by: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Change made by a chat command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Toggle {
+    Off,
+    On,
+}
+
+impl Toggle {
+    /// Confirmation posted to the channel
+    pub fn acknowledgement(&self) -> &'static str {
+        match self {
+            Toggle::Off => "Bot paused in this channel. Type !boton to turn it back on.",
+            Toggle::On => "Bot is back on in this channel. Type !botoff to pause it.",
+        }
+    }
+}
+
+/// Opt-outs persisted to a JSON file, so they survive restarts
+pub struct OptOutStore {
+    config: ConsentConfig,
+    entries: Mutex<Vec<OptOut>>,
+}
+
+impl OptOutStore {
+    pub fn open(config: ConsentConfig) -> Self {
+        let entries: Vec<OptOut> = read_json(&config.path);
+        Self {
+            config,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    pub fn is_opted_out(&self, channel: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|o| o.channel.eq_ignore_ascii_case(channel))
+    }
+
+    /// Returns false if the channel already was opted out.
+    pub fn opt_out(&self, channel: &str, by: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .iter()
+            .any(|o| o.channel.eq_ignore_ascii_case(channel))
+        {
+            return false;
+        }
+
+        entries.push(OptOut {
+            channel: channel.to_lowercase(),
+            by: by.to_string(),
+            at: Utc::now(),
+        });
+        self.save(&entries);
+        true
+    }
+
+    /// Returns false if the channel wasn't opted out.
+    pub fn opt_in(&self, channel: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|o| !o.channel.eq_ignore_ascii_case(channel));
+        if entries.len() == before {
+            return false;
+        }
+
+        self.save(&entries);
+        true
+    }
+
+    pub fn list(&self) -> Vec<OptOut> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    fn save(&self, entries: &[OptOut]) {
+        if let Err(err) = write_json(&self.config.path, entries) {
+            error!("Failed to save opt-outs to {}: {}", self.config.path, err);
+        }
+    }
+}
+
+pub static OPT_OUTS: Lazy<OptOutStore> =
+    Lazy::new(|| OptOutStore::open(CONFIG.get().consent.clone()));
+
+pub fn is_command(text: &str) -> bool {
+    let command = text.split_whitespace().next().unwrap_or_default();
+    command.eq_ignore_ascii_case(OFF_COMMAND) || command.eq_ignore_ascii_case(ON_COMMAND)
+}
+
+/// Apply `!botoff`/`!boton` sent by the broadcaster or a moderator, returns the last change.
+pub fn handle_commands(account: &Account, chats: &[UserMsg]) -> Option<Toggle> {
+    let mut change = None;
+    for msg in chats.iter().filter(|m| is_command(&m.message)) {
+        if !msg.is_moderator() {
+            info!(
+                "Ignored {} from {}, not a moderator of {}",
+                msg.message, msg.sender, account.channel
+            );
+            continue;
+        }
+
+        let off = msg.message.to_lowercase().starts_with(OFF_COMMAND);
+        let changed = if off {
+            OPT_OUTS.opt_out(&account.channel, &msg.sender)
+        } else {
+            OPT_OUTS.opt_in(&account.channel)
+        };
+        if changed {
+            let toggle = if off { Toggle::Off } else { Toggle::On };
+            info!(
+                "{} turned the bot {:?} in {}",
+                msg.sender, toggle, account.channel
+            );
+            change = Some(toggle);
+        }
+    }
+
+    change
+}
+
+/// Why the account may not post in its channel, None if it may
+pub fn blocked_reason(account: &Account) -> Option<String> {
+    if !account.broadcaster_consent {
+        return Some(format!(
+            "{} has no broadcaster_consent for {}",
+            account.account_name, account.channel
+        ));
+    }
+    if OPT_OUTS.is_opted_out(&account.channel) {
+        return Some(format!(
+            "{} opted out with {}",
+            account.channel, OFF_COMMAND
+        ));
+    }
+
+    None
+}
+
+/// Add the disclosure prefix and suffix, shortening the text to stay within `max_message_length`.
+pub fn disclose(account: &Account, text: &str) -> String {
+    let Some(disclosure) = &account.disclosure else {
+        return text.to_string();
+    };
+
+    let extra = disclosure.prefix.chars().count() + disclosure.suffix.chars().count();
+    let room = account.max_message_length.saturating_sub(extra);
+    let body: String = text.chars().take(room).collect();
+    format!("{}{}{}", disclosure.prefix, body, disclosure.suffix)
+}
+
+// account_name:channel, when the disclosure notice was last sent
+static LAST_NOTICE: Lazy<Mutex<HashMap<String, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The disclosure notice if it is due, marking it as sent.
+pub fn take_due_notice(account: &Account) -> Option<String> {
+    let disclosure = account.disclosure.as_ref()?;
+    let notice = disclosure.notice.as_ref()?;
+
+    let mut last = LAST_NOTICE.lock().unwrap();
+    let key = channel_key(account);
+    let interval = Duration::from_secs(disclosure.notice_interval);
+    if last.get(&key).is_some_and(|at| at.elapsed() < interval) {
+        return None;
+    }
+
+    last.insert(key, Instant::now());
+    Some(notice.clone())
+}
@@ -0,0 +1,197 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Account, ExperimentsConfig, InstructionVariant, CONFIG},
+    persist::{read_json, write_json},
+    twitch::Reaction,
+};
+
+/// What happened to a generated message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Outcome {
+    pub sent: bool,
+    /// send error or NOTICE `msg-id`
+    pub rejected: Option<String>,
+    /// messages replying to or mentioning the bot within the watch window
+    pub replies: u32,
+    /// removed by a moderator within the watch window
+    pub deleted: bool,
+    /// true once the send failed or chat was watched, a sent message stays
+    /// unobserved when watching chat failed
+    pub observed: bool,
+}
+
+impl From<Reaction> for Outcome {
+    fn from(reaction: Reaction) -> Self {
+        Self {
+            sent: reaction.rejected.is_none(),
+            rejected: reaction.rejected,
+            replies: reaction.replies,
+            deleted: reaction.deleted,
+            observed: true,
+        }
+    }
+}
+
+/// One generation with a variant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trial {
+    pub id: u64,
+    pub account: String,
+    pub channel: String,
+    pub variant: String,
+    pub message: String,
+    pub at: DateTime<Utc>,
+    pub outcome: Outcome,
+}
+
+/// Results of one variant, see [`ExperimentLog::stats`]
+#[derive(Debug, Clone, Default)]
+pub struct VariantStats {
+    pub variant: String,
+    pub trials: u64,
+    pub sent: u64,
+    pub rejected: u64,
+    pub deleted: u64,
+    /// sent messages whose reaction was watched
+    pub watched: u64,
+    /// sent messages that got at least one reply
+    pub replied: u64,
+    pub replies: u64,
+}
+
+impl VariantStats {
+    /// Share of watched messages that got a reply
+    pub fn reply_rate(&self) -> f64 {
+        if self.watched == 0 {
+            return 0.0;
+        }
+        self.replied as f64 / self.watched as f64
+    }
+}
+
+/// Pick one of the account's variants by weight, None if it has none.
+pub fn pick_variant(account: &Account) -> Option<&InstructionVariant> {
+    let weights = account.variants.iter().map(|v| v.weight);
+    match WeightedIndex::new(weights) {
+        Ok(index) => account.variants.get(index.sample(&mut rand::thread_rng())),
+        Err(err) => {
+            if !account.variants.is_empty() {
+                warn!(
+                    "{}: variant weights unusable ({}), using the first",
+                    account.account_name, err
+                );
+            }
+            account.variants.first()
+        }
+    }
+}
+
+/// Trials persisted to a JSON file
+pub struct ExperimentLog {
+    config: ExperimentsConfig,
+    trials: Mutex<Vec<Trial>>,
+}
+
+impl ExperimentLog {
+    pub fn open(config: ExperimentsConfig) -> Self {
+        let trials: Vec<Trial> = read_json(&config.path);
+        Self {
+            config,
+            trials: Mutex::new(trials),
+        }
+    }
+
+    /// Record a generated message, returns the trial id for [`Self::finish`].
+    pub fn start(&self, account: &Account, variant: &str, message: &str) -> u64 {
+        let mut trials = self.trials.lock().unwrap();
+        let id = trials.last().map_or(1, |t| t.id + 1);
+        trials.push(Trial {
+            id,
+            account: account.account_name.clone(),
+            channel: account.channel.clone(),
+            variant: variant.to_string(),
+            message: message.to_string(),
+            at: Utc::now(),
+            outcome: Outcome::default(),
+        });
+
+        let excess = trials.len().saturating_sub(self.config.max_trials);
+        trials.drain(..excess);
+        self.save(&trials);
+
+        id
+    }
+
+    pub fn finish(&self, id: u64, outcome: Outcome) {
+        let mut trials = self.trials.lock().unwrap();
+        if let Some(trial) = trials.iter_mut().find(|t| t.id == id) {
+            trial.outcome = outcome;
+            self.save(&trials);
+        }
+    }
+
+    /// Delete trials of generated messages with any of `messages`.
+    pub fn forget(&self, messages: &[String]) {
+        let mut trials = self.trials.lock().unwrap();
+        let before = trials.len();
+        trials.retain(|t| !messages.contains(&t.message));
+        if trials.len() < before {
+            self.save(&trials);
+        }
+    }
+
+    /// Trials of the account, or all, oldest first
+    pub fn trials(&self, account: Option<&str>) -> Vec<Trial> {
+        self.trials
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| account.is_none_or(|a| a == t.account))
+            .cloned()
+            .collect()
+    }
+
+    /// Results per variant, trials still being watched are left out
+    pub fn stats(&self, account: &str) -> Vec<VariantStats> {
+        let mut stats: BTreeMap<String, VariantStats> = BTreeMap::new();
+        for trial in self.trials(Some(account)) {
+            let o = &trial.outcome;
+            // still being watched
+            if !o.observed && !o.sent {
+                continue;
+            }
+
+            let s = stats
+                .entry(trial.variant.clone())
+                .or_insert_with(|| VariantStats {
+                    variant: trial.variant.clone(),
+                    ..Default::default()
+                });
+            s.trials += 1;
+            s.sent += u64::from(o.sent);
+            s.rejected += u64::from(o.rejected.is_some());
+            s.deleted += u64::from(o.deleted);
+            s.watched += u64::from(o.observed && o.sent);
+            s.replied += u64::from(o.replies > 0);
+            s.replies += u64::from(o.replies);
+        }
+
+        stats.into_values().collect()
+    }
+
+    fn save(&self, trials: &[Trial]) {
+        if let Err(err) = write_json(&self.config.path, trials) {
+            error!("Failed to save trials to {}: {}", self.config.path, err);
+        }
+    }
+}
+
+pub static EXPERIMENTS: Lazy<ExperimentLog> =
+    Lazy::new(|| ExperimentLog::open(CONFIG.get().experiments.clone()));
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use thiserror::Error;
+
+use crate::config::{HttpConfig, CONFIG};
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("Failed to read CA bundle {0}: {1}")]
+    CaBundle(String, std::io::Error),
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+}
+
+/// Build an HTTP client from the given settings.
+pub fn build_client(config: &HttpConfig) -> Result<reqwest::Client, HttpError> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .read_timeout(Duration::from_secs(config.read_timeout))
+        .user_agent(config.user_agent.as_str())
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout))
+        .pool_max_idle_per_host(config.pool_max_idle_per_host);
+
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+    }
+
+    if let Some(path) = &config.ca_bundle {
+        let pem = std::fs::read(path).map_err(|e| HttpError::CaBundle(path.clone(), e))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Client shared by providers and Twitch helpers
+pub static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    build_client(&CONFIG.get().http)
+        .unwrap_or_else(|e| panic!("Failed to build HTTP client: {}", e))
+});
@@ -0,0 +1,330 @@
+use std::{collections::HashSet, path::Path, sync::Mutex};
+
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    config::{Account, AdminConfig, CONFIG},
+    twitch::UserMsg,
+};
+
+/// Stops posting, `!kill <account>` for a single account
+pub const KILL_COMMAND: &str = "!kill";
+/// Lifts `!kill`, `!resume <account>` for a single account
+pub const RESUME_COMMAND: &str = "!resume";
+/// `admin.token` of config.template.yml, never accepted
+const PLACEHOLDER_TOKEN: &str = "change-me";
+
+/// Switches flipped at runtime by signal, admin HTTP call or chat command
+#[derive(Debug, Default)]
+struct Switches {
+    global: bool,
+    /// lowercase account names
+    accounts: HashSet<String>,
+}
+
+static SWITCHES: Lazy<Mutex<Switches>> = Lazy::new(|| Mutex::new(Switches::default()));
+
+/// Turn the switch on or off, for every account if `account` is None.
+pub fn set(account: Option<&str>, on: bool, by: &str) {
+    let mut switches = SWITCHES.lock().unwrap();
+    match account {
+        None => switches.global = on,
+        Some(name) if on => {
+            switches.accounts.insert(name.to_lowercase());
+        }
+        Some(name) => {
+            switches.accounts.remove(&name.to_lowercase());
+        }
+    }
+    warn!(
+        "Kill switch {} for {} by {}",
+        if on { "on" } else { "off" },
+        account.unwrap_or("all accounts"),
+        by
+    );
+}
+
+/// Why the account may not post, None if the switch is off for it.
+///
+/// Sentinel files are checked on every call, so creating one takes effect before the next send.
+pub fn killed_reason(account: &Account) -> Option<String> {
+    let name = account.account_name.to_lowercase();
+    {
+        let switches = SWITCHES.lock().unwrap();
+        if switches.global {
+            return Some("kill switch is on".into());
+        }
+        if switches.accounts.contains(&name) {
+            return Some(format!("kill switch is on for {}", account.account_name));
+        }
+    }
+
+    let file = &CONFIG.get().kill_switch.file;
+    if Path::new(file).exists() {
+        return Some(format!("kill switch file {} exists", file));
+    }
+    let own = format!("{}.{}", file, name);
+    if Path::new(&own).exists() {
+        return Some(format!("kill switch file {} exists", own));
+    }
+
+    None
+}
+
+pub fn is_command(text: &str) -> bool {
+    let command = text.split_whitespace().next().unwrap_or_default();
+    command.eq_ignore_ascii_case(KILL_COMMAND) || command.eq_ignore_ascii_case(RESUME_COMMAND)
+}
+
+/// Apply `!kill`/`!resume` sent by an owner, anyone else is ignored.
+pub fn handle_commands(chats: &[UserMsg]) {
+    let owners = &CONFIG.get().kill_switch.owners;
+    for msg in chats.iter().filter(|m| is_command(&m.message)) {
+        if !owners.iter().any(|o| o.eq_ignore_ascii_case(&msg.sender)) {
+            debug!("Ignored {} from {}, not an owner", msg.message, msg.sender);
+            continue;
+        }
+
+        let mut words = msg.message.split_whitespace();
+        let on = words
+            .next()
+            .is_some_and(|c| c.eq_ignore_ascii_case(KILL_COMMAND));
+        let account = words.next();
+        if let Some(name) = account.filter(|n| !is_account(n)) {
+            warn!(
+                "Ignored {} from {}, {} is not a configured account",
+                msg.message, msg.sender, name
+            );
+            continue;
+        }
+        set(account, on, &msg.sender);
+    }
+}
+
+fn is_account(name: &str) -> bool {
+    CONFIG
+        .get()
+        .accounts
+        .iter()
+        .any(|a| a.account_name.eq_ignore_ascii_case(name))
+}
+
+/// Turn the global switch on with SIGUSR1.
+///
+/// A repeated signal keeps it on, only `/resume` or `!resume` turn it off.
+#[cfg(unix)]
+pub fn spawn_signal_handler() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            set(None, true, "SIGUSR1");
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_signal_handler() -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Serve the admin endpoint:
+///
+/// - `POST /kill` and `POST /kill/<account>` turn the switch on
+/// - `POST /resume` and `POST /resume/<account>` turn it off
+/// - `GET /kill` shows the switches
+///
+/// Refuses to start without a token, or with the placeholder from config.template.yml.
+pub async fn spawn_admin(config: AdminConfig) -> std::io::Result<()> {
+    let token = match config.token.as_deref().map(str::trim) {
+        None | Some("") => return Err(std::io::Error::other("admin.token is not set")),
+        Some(PLACEHOLDER_TOKEN) => {
+            return Err(std::io::Error::other(
+                "admin.token is still the placeholder from config.template.yml",
+            ))
+        }
+        Some(token) => token.to_string(),
+    };
+    let listener = TcpListener::bind(&config.bind).await?;
+    info!("Admin endpoint listening on {}", config.bind);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let token = token.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(stream, &peer.to_string(), &token).await {
+                            debug!("Admin request from {} failed: {}", peer, err);
+                        }
+                    });
+                }
+                Err(err) => error!("Admin endpoint: {}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// What an admin request asks for
+#[derive(Debug, PartialEq)]
+enum Route {
+    Unauthorized,
+    Status,
+    /// turn the switch on or off, for every account if None
+    Switch {
+        account: Option<String>,
+        on: bool,
+    },
+    NotFound,
+}
+
+/// Route a raw HTTP request, checking its bearer token.
+fn route(request: &str, token: &str) -> Route {
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let expected = format!("Bearer {}", token);
+    let authorized = lines.any(|l| {
+        l.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("authorization")
+                && constant_time_eq(value.trim().as_bytes(), expected.as_bytes())
+        })
+    });
+    if !authorized {
+        return Route::Unauthorized;
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["kill"]) => Route::Status,
+        ("POST", [command @ ("kill" | "resume"), rest @ ..]) if rest.len() <= 1 => Route::Switch {
+            account: rest.first().map(|a| a.to_string()),
+            on: *command == "kill",
+        },
+        _ => Route::NotFound,
+    }
+}
+
+/// Answer a single HTTP/1.1 request and close the connection.
+async fn serve(mut stream: TcpStream, peer: &str, token: &str) -> std::io::Result<()> {
+    const MAX_REQUEST_SIZE: usize = 8192;
+    let mut request = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let (status, body) = match route(&request, token) {
+        Route::Unauthorized => ("401 Unauthorized", "unauthorized\n".to_string()),
+        Route::Status => ("200 OK", status()),
+        Route::Switch { account, .. } if account.as_deref().is_some_and(|a| !is_account(a)) => {
+            ("404 Not Found", "unknown account\n".to_string())
+        }
+        Route::Switch { account, on } => {
+            set(account.as_deref(), on, &format!("admin ({})", peer));
+            ("200 OK", status())
+        }
+        Route::NotFound => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Compare without returning early, so timing doesn't reveal how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// One line per account with the reason it is stopped, or `running`
+fn status() -> String {
+    CONFIG
+        .get()
+        .accounts
+        .iter()
+        .map(|a| {
+            format!(
+                "{}: {}\n",
+                a.account_name,
+                killed_reason(a).unwrap_or_else(|| "running".into())
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secreT", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secret2", b"Bearer secret"));
+        assert!(!constant_time_eq(b"", b"Bearer secret"));
+    }
+
+    #[test]
+    fn routing() {
+        let request =
+            |head: &str, auth: &str| format!("{} HTTP/1.1\r\nHost: x\r\n{}\r\n\r\n", head, auth);
+        let auth = "authorization:  Bearer s3cret ";
+        let switch = |account: Option<&str>, on| Route::Switch {
+            account: account.map(str::to_string),
+            on,
+        };
+
+        let cases = [
+            (request("GET /kill", auth), Route::Status),
+            (request("POST /kill", auth), switch(None, true)),
+            (request("POST /kill/", auth), switch(None, true)),
+            (
+                request("POST /kill/chatbot", auth),
+                switch(Some("chatbot"), true),
+            ),
+            (request("POST /resume", auth), switch(None, false)),
+            (
+                request("POST /resume/chatbot", auth),
+                switch(Some("chatbot"), false),
+            ),
+            (request("POST /kill/a/b", auth), Route::NotFound),
+            (request("GET /resume", auth), Route::NotFound),
+            (request("DELETE /kill", auth), Route::NotFound),
+            (request("POST /kill", ""), Route::Unauthorized),
+            (
+                request("POST /kill", "Authorization: Bearer wrong"),
+                Route::Unauthorized,
+            ),
+            (
+                request("POST /kill", "X-Authorization: Bearer s3cret"),
+                Route::Unauthorized,
+            ),
+        ];
+        for (req, expected) in cases {
+            assert_eq!(route(&req, "s3cret"), expected, "{:?}", req);
+        }
+    }
+}
@@ -1,5 +1,18 @@
+#![allow(non_snake_case)]
+
 pub mod chat_model;
 pub mod config;
+pub mod consent;
+pub mod experiments;
+pub mod http;
+pub mod kill_switch;
 pub mod logger;
+pub mod metrics;
+pub mod persist;
+pub mod safety;
+pub mod sanctions;
+pub mod store;
 pub mod twitch;
+pub mod usage;
+pub mod viewers;
 pub mod workflows;
@@ -14,6 +14,12 @@ impl LoggerSetup {
     }
 }
 
+impl Default for LoggerSetup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Get RUST_LOG as LevelFilter
 fn get_log_level() -> LevelFilter {
     let level = std::env::var("RUST_LOG")
@@ -1,32 +1,113 @@
 use std::time::Duration;
 
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 
 use tokio::time::{sleep, timeout};
 use Twitch_AI_Chatbot::{
+    chat_model::service::instruction,
     config::{
         channel::{can_execute, init_channels, schedule_next_execution_in},
+        reload::spawn_watcher,
         OperatingMode, CONFIG,
     },
+    consent::OPT_OUTS,
+    experiments::EXPERIMENTS,
+    http::HTTP_CLIENT,
+    kill_switch::{self, killed_reason},
     logger::LoggerSetup,
-    twitch::utils::is_online,
+    metrics,
+    sanctions::{suspended_reason, SANCTIONS},
+    twitch::{room_state, utils::is_online},
+    usage::{UsageQuery, USAGE},
     workflows::recv_and_send_msg::recv_and_send_msg,
 };
 
 #[tokio::main]
 async fn main() {
     LoggerSetup::new();
-    info!("Available chatbots: {}", CONFIG.accounts.len());
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("usage") => return print_usage(args.get(1).cloned()),
+        Some("validate") => std::process::exit(if validate_instructions() { 0 } else { 1 }),
+        Some("experiments") => return print_experiments(args.get(1).cloned()),
+        Some("reinstate") => std::process::exit(if reinstate(args.get(1)) { 0 } else { 1 }),
+        Some(other) => {
+            eprintln!(
+                "Unknown command: {}\nUsage: Twitch-AI-Chatbot [usage [account] | experiments [account] | reinstate <account> | validate]",
+                other
+            );
+            std::process::exit(2);
+        }
+    }
+
+    if !instruction::load_all(&CONFIG.get().accounts) {
+        error!("Invalid instructions, run `Twitch-AI-Chatbot validate` for details");
+        std::process::exit(1);
+    }
+
+    info!("Available chatbots: {}", CONFIG.get().accounts.len());
+    for account in CONFIG
+        .get()
+        .accounts
+        .iter()
+        .filter(|a| !a.broadcaster_consent)
+    {
+        warn!(
+            "{} will not post to {} until broadcaster_consent is set",
+            account.account_name, account.channel
+        );
+    }
+    for opt_out in OPT_OUTS.list() {
+        info!(
+            "Bot is off in {} ({} at {})",
+            opt_out.channel, opt_out.by, opt_out.at
+        );
+    }
+
+    for account in &CONFIG.get().accounts {
+        if let Some(reason) = suspended_reason(account) {
+            warn!("{}", reason);
+        }
+    }
 
     init_channels();
+    if let Err(err) = spawn_watcher() {
+        warn!("Hot reload disabled: {}", err);
+    }
+    if let Err(err) = kill_switch::spawn_signal_handler() {
+        warn!("SIGUSR1 kill switch disabled: {}", err);
+    }
+    metrics::spawn_logger(Duration::from_secs(60 * 10));
+    if let Some(admin) = CONFIG.get().kill_switch.admin.clone() {
+        if let Err(err) = kill_switch::spawn_admin(admin).await {
+            error!("Admin endpoint disabled: {}", err);
+        }
+    }
 
     loop {
-        for account in &CONFIG.accounts {
+        // re-read every pass so reloaded accounts are picked up
+        let config = CONFIG.get();
+        for account in &config.accounts {
             if !can_execute(account) {
                 continue;
             }
 
-            let online_status = is_online(account).await;
+            // timed out until the timeout ends, banned until reinstated
+            if let Some(sanction) = SANCTIONS.suspension(account) {
+                let wait = sanction.remaining().unwrap_or(Duration::from_secs(60 * 10));
+                debug!(
+                    "{} suspended in {}, next check in {}s",
+                    account.account_name,
+                    account.channel,
+                    wait.as_secs()
+                );
+                schedule_next_execution_in(account, wait);
+                continue;
+            }
+
+            let online_status = is_online(account, &HTTP_CLIENT).await;
             debug!(
                 "Channel {} status: {} (operating mode: {:?})",
                 account.channel,
@@ -43,10 +124,15 @@ async fn main() {
                 continue;
             }
 
+            // the cycle still reads chat, so `!resume` and `!boton` are seen
+            if let Some(reason) = killed_reason(account) {
+                info!("{}: {}, reading chat only", account.account_name, reason);
+            }
+
             // timeout if it exeeds set time.
             match timeout(
                 Duration::from_secs(account.timeout.try_into().unwrap()),
-                recv_and_send_msg(account),
+                recv_and_send_msg(account, &HTTP_CLIENT),
             )
             .await
             {
@@ -57,13 +143,154 @@ async fn main() {
                 ),
             }
 
-            // update hashmap
-            schedule_next_execution_in(
-                account,
-                Duration::from_secs(account.interval.try_into().unwrap()),
-            );
+            // update hashmap, slow mode longer than the interval stretches it
+            let slow = room_state::room_state(&account.channel).map_or(0, |r| r.slow);
+            let interval: u64 = account.interval.try_into().unwrap();
+            if slow > interval {
+                info!("Slow mode in {}, next cycle in {}s", account.channel, slow);
+            }
+            schedule_next_execution_in(account, Duration::from_secs(interval.max(slow)));
 
             sleep(Duration::from_secs(1)).await;
         }
     }
 }
+
+/// Print recorded token usage and estimated cost.
+fn print_usage(account: Option<String>) {
+    let query = UsageQuery {
+        account,
+        ..Default::default()
+    };
+
+    println!(
+        "{:<10} {:<20} {:<20} {:>8} {:>12} {:>12} {:>10}",
+        "day", "account", "model", "requests", "prompt", "completion", "cost_usd"
+    );
+    for e in USAGE.entries(&query) {
+        println!(
+            "{:<10} {:<20} {:<20} {:>8} {:>12} {:>12} {:>10.4}",
+            e.day, e.account, e.model, e.requests, e.prompt_tokens, e.completion_tokens, e.cost_usd
+        );
+    }
+
+    let t = USAGE.totals(&query);
+    println!(
+        "{:<10} {:<20} {:<20} {:>8} {:>12} {:>12} {:>10.4}",
+        "total", "", "", t.requests, t.prompt_tokens, t.completion_tokens, t.cost_usd
+    );
+}
+
+/// Print results of every instruction variant.
+fn print_experiments(account: Option<String>) {
+    println!(
+        "{:<20} {:<20} {:>8} {:>8} {:>10} {:>8} {:>8} {:>8} {:>10}",
+        "account",
+        "variant",
+        "trials",
+        "sent",
+        "rejected",
+        "deleted",
+        "watched",
+        "replies",
+        "reply_rate"
+    );
+    for a in &CONFIG.get().accounts {
+        if account.as_ref().is_some_and(|name| *name != a.account_name) {
+            continue;
+        }
+        for s in EXPERIMENTS.stats(&a.account_name) {
+            println!(
+                "{:<20} {:<20} {:>8} {:>8} {:>10} {:>8} {:>8} {:>8} {:>10.3}",
+                a.account_name,
+                s.variant,
+                s.trials,
+                s.sent,
+                s.rejected,
+                s.deleted,
+                s.watched,
+                s.replies,
+                s.reply_rate()
+            );
+        }
+    }
+}
+
+/// Lift the bans of an account after it was unbanned, returns false if there were none.
+fn reinstate(account: Option<&String>) -> bool {
+    let Some(name) = account else {
+        eprintln!("Usage: Twitch-AI-Chatbot reinstate <account>");
+        return false;
+    };
+
+    let mut lifted = 0;
+    for a in CONFIG
+        .get()
+        .accounts
+        .iter()
+        .filter(|a| a.account_name.eq_ignore_ascii_case(name))
+    {
+        let n = SANCTIONS.reinstate(a);
+        if n > 0 {
+            println!("{} may post in {} again", a.account_name, a.channel);
+        }
+        lifted += n;
+    }
+    if lifted == 0 {
+        println!("{} has no active bans", name);
+    }
+
+    lifted > 0
+}
+
+/// Validate every account's instructions and print a preview rendered with sample history.
+fn validate_instructions() -> bool {
+    let mut all_valid = true;
+
+    for (account, path) in CONFIG
+        .get()
+        .accounts
+        .iter()
+        .flat_map(|a| a.instructions().into_iter().map(move |p| (a, p)))
+    {
+        println!("== {} ({}) ==", account.account_name, path);
+
+        let loaded = match instruction::load_instruction(path) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                println!("error: {}\n", err);
+                all_valid = false;
+                continue;
+            }
+        };
+
+        if loaded.sources.len() > 1 {
+            let files: Vec<String> = loaded
+                .sources
+                .iter()
+                .map(|(p, _)| p.display().to_string())
+                .collect();
+            println!("built from: {}", files.join(", "));
+        }
+
+        let issues = instruction::validate(&loaded, account);
+        for issue in &issues {
+            println!("error: {}", issue);
+        }
+        all_valid &= issues.is_empty();
+
+        let ctx = instruction::sample_context(account);
+        if let Ok(messages) = instruction::render_messages(&loaded, &ctx) {
+            println!(
+                "preview (~{} tokens):",
+                instruction::estimate_tokens(&messages)
+            );
+            for m in messages {
+                println!("[{}]\n{}", m.role, m.content);
+            }
+        }
+        println!();
+    }
+
+    all_valid
+}
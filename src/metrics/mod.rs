@@ -0,0 +1,61 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use log::info;
+
+/// Process-wide counters
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+}
+
+/// Point-in-time copy of [`Metrics`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+impl Metrics {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub static METRICS: Metrics = Metrics {
+    cache_hits: AtomicU64::new(0),
+    cache_misses: AtomicU64::new(0),
+};
+
+/// Log the counters every `every` while they change.
+pub fn spawn_logger(every: Duration) {
+    tokio::spawn(async move {
+        let mut last = MetricsSnapshot::default();
+        loop {
+            tokio::time::sleep(every).await;
+            let now = METRICS.snapshot();
+            if now == last {
+                continue;
+            }
+
+            let lookups = now.cache_hits + now.cache_misses;
+            info!(
+                "Response cache: {} hits, {} misses ({:.0}% hit rate)",
+                now.cache_hits,
+                now.cache_misses,
+                now.cache_hits as f64 * 100.0 / lookups.max(1) as f64
+            );
+            last = now;
+        }
+    });
+}
@@ -0,0 +1,33 @@
+use std::{fs, io, path::Path};
+
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Read a JSON state file, falling back to the default if it is missing or broken.
+pub fn read_json<T: DeserializeOwned + Default>(path: &str) -> T {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return T::default(),
+        Err(err) => {
+            warn!("Failed to read {}: {}", path, err);
+            return T::default();
+        }
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        warn!("Failed to parse {}: {}", path, err);
+        T::default()
+    })
+}
+
+/// Write a JSON state file atomically, creating parent directories.
+pub fn write_json<T: Serialize + ?Sized>(path: &str, value: &T) -> io::Result<()> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(tmp, path)
+}
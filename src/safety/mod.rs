@@ -0,0 +1,305 @@
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    config::{Account, LinkPolicy, ModerationConfig, SafetyConfig, CONFIG},
+    twitch::UserMsg,
+};
+
+/// Reason a text was blocked
+#[derive(Debug, Clone, Error)]
+pub enum Blocked {
+    #[error("blocked word \"{0}\"")]
+    Word(String),
+    #[error("blocked pattern {0}")]
+    Pattern(String),
+    #[error("link to {0}")]
+    Link(String),
+    #[error("flagged by moderation: {0}")]
+    Flagged(String),
+    /// moderation could not be reached, nothing unchecked is sent
+    #[error("moderation failed: {0}")]
+    Moderation(String),
+}
+
+// http(s)://..., www.... or a bare domain with a common TLD
+static LINK_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\b(?:https?://|www\.)[^\s]+|\b[a-z0-9-]+(?:\.[a-z0-9-]+)*\.(?:com|net|org|tv|gg|io|co|me|ly|xyz|jp|de|uk|info|link|app|dev)\b(?:/[^\s]*)?",
+    )
+    .unwrap()
+});
+
+/// Blocklists and link policy, no network involved
+pub fn check_local(config: &SafetyConfig, text: &str) -> Result<(), Blocked> {
+    let lower = text.to_lowercase();
+    for word in &config.blocked_words {
+        if contains_word(&lower, &word.to_lowercase()) {
+            return Err(Blocked::Word(word.clone()));
+        }
+    }
+
+    for pattern in &config.blocked_patterns {
+        if pattern.0.is_match(text) {
+            return Err(Blocked::Pattern(pattern.0.as_str().to_string()));
+        }
+    }
+
+    for link in LINK_RE.find_iter(text) {
+        let domain = link_domain(link.as_str());
+        let allowed = match config.links {
+            LinkPolicy::ALLOW => true,
+            LinkPolicy::BLOCK => false,
+            LinkPolicy::ALLOWLIST => config.allowed_domains.iter().any(|d| {
+                let d = d.to_lowercase();
+                domain == d || domain.ends_with(&format!(".{}", d))
+            }),
+        };
+        if !allowed {
+            return Err(Blocked::Link(domain));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whole-word match for latin words, substring otherwise (e.g. Japanese has no spaces)
+fn contains_word(haystack: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+    if !word.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return haystack.contains(word);
+    }
+
+    haystack.match_indices(word).any(|(i, _)| {
+        let before = haystack[..i].chars().next_back();
+        let after = haystack[i + word.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Host of the link without `www.`, punctuation the link ran into, like "twitch.tv).", is dropped
+fn link_domain(link: &str) -> String {
+    let lower = link.to_lowercase();
+    let rest = lower.split_once("://").map_or(lower.as_str(), |(_, r)| r);
+    let host = rest.split(['/', '?', '#', ':']).next().unwrap_or(rest);
+    host.trim_start_matches("www.")
+        .trim_end_matches(|c: char| !c.is_alphanumeric())
+        .to_string()
+}
+
+/// Run a generated message through every configured check.
+pub async fn check_output(
+    account: &Account,
+    client: &reqwest::Client,
+    text: &str,
+) -> Result<(), Blocked> {
+    let config = &account.safety;
+    check_local(config, text)?;
+
+    if let Some(moderation) = &config.moderation {
+        let verdicts = moderate(moderation, client, &[text.to_string()])
+            .await
+            .map_err(Blocked::Moderation)?;
+        if let Some(Some(categories)) = verdicts.into_iter().next() {
+            return Err(Blocked::Flagged(categories));
+        }
+    }
+
+    Ok(())
+}
+
+/// Leave out viewer messages failing the checks, so they never reach the prompt.
+///
+/// If moderation is unreachable the blocklists still apply.
+pub async fn filter_history(
+    account: &Account,
+    client: &reqwest::Client,
+    chats: Vec<UserMsg>,
+) -> Vec<UserMsg> {
+    let config = &account.safety;
+    if !config.filter_history {
+        return chats;
+    }
+
+    let mut kept: Vec<UserMsg> = chats
+        .into_iter()
+        .filter(|m| match check_local(config, &m.message) {
+            Ok(()) => true,
+            Err(reason) => {
+                warn!("Left {}'s message out of history: {}", m.sender, reason);
+                false
+            }
+        })
+        .collect();
+
+    let Some(moderation) = config.moderation.as_ref().filter(|m| m.check_history) else {
+        return kept;
+    };
+    if kept.is_empty() {
+        return kept;
+    }
+
+    let texts: Vec<String> = kept.iter().map(|m| m.message.clone()).collect();
+    match moderate(moderation, client, &texts).await {
+        Ok(verdicts) => {
+            let mut verdicts = verdicts.into_iter();
+            kept.retain(|m| match verdicts.next().flatten() {
+                Some(categories) => {
+                    warn!(
+                        "Left {}'s message out of history: flagged by moderation: {}",
+                        m.sender, categories
+                    );
+                    false
+                }
+                None => true,
+            });
+        }
+        Err(err) => warn!("History moderation skipped: {}", err),
+    }
+
+    kept
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: std::collections::BTreeMap<String, bool>,
+}
+
+/// Flagged categories per input, None for inputs that passed
+async fn moderate(
+    config: &ModerationConfig,
+    client: &reqwest::Client,
+    inputs: &[String],
+) -> Result<Vec<Option<String>>, String> {
+    let api_key = config
+        .api_key
+        .clone()
+        .unwrap_or_else(|| CONFIG.get().openai.api_key.clone());
+
+    let resp = client
+        .post(&config.url)
+        .bearer_auth(api_key)
+        .json(&serde_json::json!({ "model": config.model, "input": inputs }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("{} {}", status, body));
+    }
+
+    let parsed: ModerationResponse = resp.json().await.map_err(|e| e.to_string())?;
+    debug!("moderation results: {}", parsed.results.len());
+
+    Ok(parsed
+        .results
+        .into_iter()
+        .map(|r| {
+            r.flagged.then(|| {
+                let flagged: Vec<&str> = r
+                    .categories
+                    .iter()
+                    .filter(|(_, hit)| **hit)
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                flagged.join(", ")
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> SafetyConfig {
+        serde_yml::from_str(yaml).unwrap()
+    }
+
+    fn check(config: &SafetyConfig, cases: &[(&str, Option<&str>)]) {
+        for (text, blocked) in cases {
+            let result = check_local(config, text).err().map(|b| b.to_string());
+            assert_eq!(result.as_deref(), *blocked, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn words() {
+        let config = config("blocked_words: [Spoiler, 死ね]");
+        check(
+            &config,
+            &[
+                ("no SPOILERS please", None),
+                ("that's a spoiler!", Some("blocked word \"Spoiler\"")),
+                ("spoiler", Some("blocked word \"Spoiler\"")),
+                ("お前死ねよ", Some("blocked word \"死ね\"")),
+                ("死なないで", None),
+            ],
+        );
+        assert!(contains_word("a-b", "a"));
+        assert!(!contains_word("abc", "b"));
+        assert!(!contains_word("anything", ""));
+    }
+
+    #[test]
+    fn patterns() {
+        let config = config(r#"blocked_patterns: ["(?i)discord\\.gg/\\w+", "\\d{3}-\\d{4}"]"#);
+        check(
+            &config,
+            &[
+                (
+                    "join Discord.gg/abc",
+                    Some(r"blocked pattern (?i)discord\.gg/\w+"),
+                ),
+                ("call 555-1234", Some(r"blocked pattern \d{3}-\d{4}")),
+                ("discord is down", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn link_policies() {
+        let text = "watch at https://www.Twitch.tv/streamer).";
+        check(&config("links: ALLOW"), &[(text, None)]);
+        check(
+            &config("links: BLOCK"),
+            &[(text, Some("link to twitch.tv")), ("no links here.", None)],
+        );
+        check(
+            &config("links: ALLOWLIST\nallowed_domains: [twitch.tv]"),
+            &[
+                (text, None),
+                ("clips at clips.twitch.tv!", None),
+                ("see (https://twitch.tv).", None),
+                ("go to evil.com", Some("link to evil.com")),
+                ("http://nottwitch.tv/x", Some("link to nottwitch.tv")),
+            ],
+        );
+    }
+
+    #[test]
+    fn domains() {
+        for (link, domain) in [
+            ("https://www.twitch.tv/a?b", "twitch.tv"),
+            ("HTTP://Example.com:8080/", "example.com"),
+            ("twitch.tv).", "twitch.tv"),
+            ("www.youtube.com,", "youtube.com"),
+        ] {
+            assert_eq!(link_domain(link), domain, "{:?}", link);
+        }
+    }
+}
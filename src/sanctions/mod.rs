@@ -0,0 +1,299 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Account, SanctionsConfig, CONFIG},
+    persist::{read_json, write_json},
+    twitch::irc::IrcMessage,
+};
+
+/// What a moderator did to the bot account
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SanctionKind {
+    Timeout,
+    Ban,
+    /// one of its messages was removed
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sanction {
+    pub account: String,
+    pub channel: String,
+    pub kind: SanctionKind,
+    pub at: DateTime<Utc>,
+    /// end of a timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+    /// text of a deleted message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Twitch id of a deleted message, the same CLEARMSG may arrive on several connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// a ban stays in effect until lifted with `reinstate`
+    #[serde(default)]
+    pub lifted: bool,
+}
+
+impl Sanction {
+    fn concerns(&self, account: &Account) -> bool {
+        self.account.eq_ignore_ascii_case(&account.account_name)
+            && self.channel.eq_ignore_ascii_case(&account.channel)
+    }
+
+    /// Time left of a timeout, None for other sanctions
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        (self.until? - Utc::now()).to_std().ok()
+    }
+
+    /// Posting is suspended because of it at `now`
+    fn suspends(&self, now: DateTime<Utc>) -> bool {
+        match self.kind {
+            SanctionKind::Ban => !self.lifted,
+            SanctionKind::Timeout => self.until.is_some_and(|until| until > now),
+            SanctionKind::Deleted => false,
+        }
+    }
+}
+
+/// Timeouts, bans and deleted messages, persisted to a JSON file.
+///
+/// The file is re-read on every access, so `reinstate` run from another process
+/// takes effect without a restart.
+pub struct SanctionStore {
+    config: SanctionsConfig,
+    lock: Mutex<()>,
+}
+
+impl SanctionStore {
+    pub fn open(config: SanctionsConfig) -> Self {
+        Self {
+            config,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Add an event, a deletion already recorded by its `message_id` is skipped.
+    pub fn record(&self, sanction: Sanction) {
+        let _guard = self.lock.lock().unwrap();
+        let mut entries: Vec<Sanction> = read_json(&self.config.path);
+        if sanction.message_id.is_some()
+            && entries.iter().any(|s| s.message_id == sanction.message_id)
+        {
+            debug!("Deletion {:?} already recorded", sanction.message_id);
+            return;
+        }
+        entries.push(sanction);
+
+        // oldest events go first, active bans are always kept
+        let excess = entries.len().saturating_sub(self.config.max_events);
+        let mut dropped = 0;
+        entries.retain(|s| {
+            let drop = dropped < excess && (s.kind != SanctionKind::Ban || s.lifted);
+            if drop {
+                dropped += 1;
+            }
+            !drop
+        });
+        self.save(&entries);
+    }
+
+    /// The sanction keeping the account from posting, None if it may post.
+    pub fn suspension(&self, account: &Account) -> Option<Sanction> {
+        let _guard = self.lock.lock().unwrap();
+        let entries: Vec<Sanction> = read_json(&self.config.path);
+        let now = Utc::now();
+        entries
+            .into_iter()
+            .filter(|s| s.concerns(account) && s.suspends(now))
+            // a ban outlasts any timeout
+            .max_by_key(|s| (s.kind == SanctionKind::Ban, s.until))
+    }
+
+    /// Lift bans of the account, returns how many were lifted.
+    pub fn reinstate(&self, account: &Account) -> usize {
+        let _guard = self.lock.lock().unwrap();
+        let mut entries: Vec<Sanction> = read_json(&self.config.path);
+        let mut lifted = 0;
+        for s in entries
+            .iter_mut()
+            .filter(|s| s.concerns(account) && s.kind == SanctionKind::Ban && !s.lifted)
+        {
+            s.lifted = true;
+            lifted += 1;
+        }
+        if lifted > 0 {
+            self.save(&entries);
+        }
+
+        lifted
+    }
+
+    /// Most recently deleted messages of the account in its channel, oldest first
+    pub fn deleted_messages(&self, account: &Account) -> Vec<String> {
+        let _guard = self.lock.lock().unwrap();
+        let entries: Vec<Sanction> = read_json(&self.config.path);
+        let mut deleted: Vec<String> = entries
+            .into_iter()
+            .rev()
+            .filter(|s| s.concerns(account) && s.kind == SanctionKind::Deleted)
+            .filter_map(|s| s.message)
+            .take(self.config.negative_examples)
+            .collect();
+        deleted.reverse();
+        deleted
+    }
+
+    fn save(&self, entries: &[Sanction]) {
+        if let Err(err) = write_json(&self.config.path, entries) {
+            error!("Failed to save sanctions to {}: {}", self.config.path, err);
+        }
+    }
+}
+
+pub static SANCTIONS: Lazy<SanctionStore> =
+    Lazy::new(|| SanctionStore::open(CONFIG.get().sanctions.clone()));
+
+/// Record CLEARCHAT, CLEARMSG or a NOTICE aimed at the account, ignores anything else.
+pub fn observe(account: &Account, msg: &IrcMessage) {
+    if !msg
+        .channel()
+        .is_some_and(|c| c.eq_ignore_ascii_case(&account.channel))
+    {
+        return;
+    }
+
+    let is_us =
+        |login: Option<&str>| login.is_some_and(|l| l.eq_ignore_ascii_case(&account.account_name));
+    let now = Utc::now();
+    let timeout = |seconds: i64| Sanction {
+        account: account.account_name.clone(),
+        channel: account.channel.clone(),
+        kind: SanctionKind::Timeout,
+        at: now,
+        until: Some(now + Duration::seconds(seconds)),
+        message: None,
+        message_id: None,
+        lifted: false,
+    };
+    let ban = || Sanction {
+        kind: SanctionKind::Ban,
+        until: None,
+        ..timeout(0)
+    };
+
+    let sanction = match msg.command.as_str() {
+        // without a target the whole chat was cleared
+        "CLEARCHAT" if is_us(msg.trailing()) => {
+            match msg.tag("ban-duration").and_then(|d| d.parse().ok()) {
+                Some(seconds) => timeout(seconds),
+                None => ban(),
+            }
+        }
+        "CLEARMSG" if is_us(msg.tag("login")) => Sanction {
+            kind: SanctionKind::Deleted,
+            until: None,
+            message: msg.trailing().map(str::to_string),
+            message_id: msg.tag("target-msg-id").map(str::to_string),
+            ..timeout(0)
+        },
+        // a send rejected because of a sanction we missed while not connected
+        "NOTICE" => match msg.tag("msg-id") {
+            Some("msg_banned") => ban(),
+            Some("msg_timedout") => {
+                // "You are timed out for 598 more seconds."
+                let seconds = msg
+                    .trailing()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .find_map(|w| w.parse().ok())
+                    .unwrap_or(60);
+                timeout(seconds)
+            }
+            _ => return,
+        },
+        _ => return,
+    };
+
+    match sanction.kind {
+        SanctionKind::Deleted => info!(
+            "Message of {} deleted in {}: {:?}",
+            account.account_name,
+            account.channel,
+            sanction.message.as_deref().unwrap_or_default()
+        ),
+        SanctionKind::Timeout => warn!(
+            "{} timed out in {} until {}",
+            account.account_name,
+            account.channel,
+            sanction
+                .until
+                .unwrap_or(now)
+                .format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+        SanctionKind::Ban => warn!(
+            "{} banned in {}, posting suspended until reinstated",
+            account.account_name, account.channel
+        ),
+    }
+    SANCTIONS.record(sanction);
+}
+
+/// Why the account is suspended in its channel, None if it isn't
+pub fn suspended_reason(account: &Account) -> Option<String> {
+    let sanction = SANCTIONS.suspension(account)?;
+    Some(match sanction.until {
+        Some(until) if sanction.kind == SanctionKind::Timeout => format!(
+            "{} is timed out in {} until {}",
+            account.account_name,
+            account.channel,
+            until.format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+        _ => format!(
+            "{} is banned in {}, run `reinstate {}` once unbanned",
+            account.account_name, account.channel, account.account_name
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deletion_recorded_once() {
+        let path = std::env::temp_dir().join(format!("sanctions-{}.json", std::process::id()));
+        let store = SanctionStore::open(SanctionsConfig {
+            path: path.to_string_lossy().into(),
+            ..Default::default()
+        });
+        let deleted = |id: &str, text: &str| Sanction {
+            account: "chatbot".into(),
+            channel: "chan".into(),
+            kind: SanctionKind::Deleted,
+            at: Utc::now(),
+            until: None,
+            message: Some(text.into()),
+            message_id: Some(id.into()),
+            lifted: false,
+        };
+
+        store.record(deleted("a", "first"));
+        store.record(deleted("a", "first"));
+        store.record(deleted("b", "second"));
+        let entries: Vec<Sanction> = read_json(&store.config.path);
+        let _ = std::fs::remove_file(&path);
+
+        let texts: Vec<_> = entries
+            .iter()
+            .filter_map(|s| s.message.as_deref())
+            .collect();
+        assert_eq!(texts, ["first", "second"]);
+    }
+}
@@ -0,0 +1,548 @@
+use std::{collections::HashMap, fs, path::Path, sync::Mutex};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    chat_model::core::{Message, Usage},
+    config::{Account, CONFIG},
+    twitch::UserMsg,
+};
+
+mod viewers;
+
+pub use viewers::Forgotten;
+
+/// Schema changes in order, `PRAGMA user_version` counts the applied ones.
+///
+/// Never edit an applied migration, append a new one.
+const MIGRATIONS: &[&str] = &[
+    // 1: chat received and generations with their outcome
+    "CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        channel TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        text TEXT NOT NULL,
+        tags TEXT NOT NULL DEFAULT '{}',
+        twitch_id TEXT UNIQUE,
+        kept INTEGER NOT NULL DEFAULT 1,
+        received_at TEXT NOT NULL
+    );
+    CREATE INDEX messages_channel ON messages (channel, id);
+    CREATE TABLE generations (
+        id INTEGER PRIMARY KEY,
+        account TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        prompt TEXT NOT NULL,
+        output TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL DEFAULT 0,
+        completion_tokens INTEGER NOT NULL DEFAULT 0,
+        latency_ms INTEGER NOT NULL,
+        outcome TEXT,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX generations_account ON generations (account, channel, id);",
+    // 2: long-term memory of viewers, by Twitch user id
+    "CREATE TABLE viewers (
+        user_id TEXT PRIMARY KEY,
+        login TEXT NOT NULL,
+        display_name TEXT NOT NULL,
+        notes TEXT NOT NULL DEFAULT '',
+        message_count INTEGER NOT NULL DEFAULT 0,
+        summarized_count INTEGER NOT NULL DEFAULT 0,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL
+    );
+    CREATE INDEX messages_user ON messages (json_extract(tags, '$.\"user-id\"'));",
+    // 3: chat waiting for the history checks, left by cycles that ended before them
+    "ALTER TABLE messages ADD COLUMN checked INTEGER NOT NULL DEFAULT 1;",
+    // 4: history checks per account, accounts sharing a channel check with their own settings
+    "CREATE TABLE message_checks (
+        message_id INTEGER NOT NULL,
+        account TEXT NOT NULL,
+        kept INTEGER NOT NULL,
+        PRIMARY KEY (message_id, account)
+    );",
+    // 5: chat each generation was generated from, and its response cache entries
+    "CREATE TABLE generation_messages (
+        generation_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL
+    );
+    CREATE INDEX generation_messages_message ON generation_messages (message_id);
+    ALTER TABLE generations ADD COLUMN cache_keys TEXT NOT NULL DEFAULT '[]';",
+];
+
+/// A generated message with what it was generated from
+#[derive(Debug, Clone)]
+pub struct Generation {
+    /// 0 until stored
+    pub id: i64,
+    pub account: String,
+    pub channel: String,
+    /// rendered prompt as sent to the model
+    pub prompt: Vec<Message>,
+    /// text after post-processing
+    pub output: String,
+    pub model: String,
+    pub usage: Usage,
+    pub latency_ms: u64,
+    /// `sent`, `repeated`, `blocked: ...`, `failed: ...` or `not sent: ...`, None while pending
+    pub outcome: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// row ids of the stored chat in the prompt
+    pub message_ids: Vec<i64>,
+    /// response cache entries the answer may be stored under
+    pub cache_keys: Vec<String>,
+}
+
+/// Chat and generations in an SQLite file
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+fn user_msg(sender: String, message: String, tags: String) -> UserMsg {
+    UserMsg {
+        sender,
+        message,
+        tags: serde_json::from_str::<HashMap<String, String>>(&tags).unwrap_or_default(),
+    }
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    // fixed width, so timestamps compare as text
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl Store {
+    /// Open the database, creating it and applying pending migrations.
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        if let Some(parent) = Path::new(path)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            let _ = fs::create_dir_all(parent);
+        }
+        Self::migrate(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::migrate(Connection::open_in_memory()?)
+    }
+
+    fn migrate(mut conn: Connection) -> rusqlite::Result<Self> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+            info!("Applied store migration {}", i + 1);
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Record chat of a channel, `kept` messages go into prompts of every account right away.
+    ///
+    /// Other messages wait in [`Store::pending_messages`] until each account checked them.
+    /// Messages already stored, by their Twitch `id` tag, are skipped.
+    pub fn record_messages(&self, channel: &str, msgs: &[UserMsg], kept: bool) {
+        let now = timestamp(Utc::now());
+        let result = (|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT OR IGNORE INTO messages (channel, sender, text, tags, twitch_id, kept, checked, received_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?7)",
+                )?;
+                for msg in msgs {
+                    insert.execute(params![
+                        channel.to_lowercase(),
+                        msg.sender,
+                        msg.message,
+                        serde_json::to_string(&msg.tags).unwrap_or_default(),
+                        msg.tags.get("id"),
+                        kept,
+                        now,
+                    ])?;
+                }
+            }
+            tx.commit()
+        })();
+        if let Err(err) = result {
+            error!("Failed to store chat of {}: {}", channel, err);
+        }
+    }
+
+    /// Messages of the channel since `since` the account didn't check yet, with their row id,
+    /// oldest first.
+    pub fn pending_messages(
+        &self,
+        channel: &str,
+        account: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> Vec<(i64, UserMsg)> {
+        let result = (|| {
+            let conn = self.conn.lock().unwrap();
+            let mut query = conn.prepare_cached(
+                "SELECT id, sender, text, tags FROM messages
+                 WHERE channel = ?1 AND checked = 0 AND received_at >= ?2
+                   AND id NOT IN (SELECT message_id FROM message_checks WHERE account = ?3)
+                 ORDER BY id",
+            )?;
+            let rows = query.query_map(
+                params![
+                    channel.to_lowercase(),
+                    since.map(timestamp).unwrap_or_default(),
+                    account.to_lowercase()
+                ],
+                |r| Ok((r.get(0)?, user_msg(r.get(1)?, r.get(2)?, r.get(3)?))),
+            )?;
+            rows.collect::<rusqlite::Result<Vec<(i64, UserMsg)>>>()
+        })();
+
+        result.unwrap_or_else(|err| {
+            error!("Failed to read pending chat of {}: {}", channel, err);
+            Vec::new()
+        })
+    }
+
+    /// Store the result of the account's history checks, (row id, passed) per message.
+    pub fn mark_checked(&self, account: &str, results: &[(i64, bool)]) {
+        let result = (|| {
+            let conn = self.conn.lock().unwrap();
+            let mut insert = conn.prepare_cached(
+                "INSERT OR REPLACE INTO message_checks (message_id, account, kept) VALUES (?1, ?2, ?3)",
+            )?;
+            for (id, kept) in results {
+                insert.execute(params![id, account.to_lowercase(), kept])?;
+            }
+            Ok::<_, rusqlite::Error>(())
+        })();
+        if let Err(err) = result {
+            error!("Failed to update stored chat: {}", err);
+        }
+    }
+
+    /// Messages of the channel since `since` kept for the `own` account with their row id,
+    /// oldest first: up to
+    /// `limit` of other senders and up to `limit` of `own`, so busy chat doesn't push out the
+    /// bot's messages.
+    ///
+    /// Messages of `hidden` senders are left out.
+    pub fn recent_messages(
+        &self,
+        channel: &str,
+        own: &str,
+        limit: usize,
+        since: Option<DateTime<Utc>>,
+        hidden: &[String],
+    ) -> Vec<(i64, UserMsg)> {
+        let result = (|| {
+            let conn = self.conn.lock().unwrap();
+            let mut query = conn.prepare_cached(
+                "SELECT id, sender, text, tags FROM (
+                    SELECT id, sender, text, tags,
+                        ROW_NUMBER() OVER (PARTITION BY lower(sender) = lower(?2) ORDER BY id DESC) AS n
+                    FROM messages
+                    WHERE channel = ?1 AND received_at >= ?3
+                      AND (kept = 1 OR id IN (
+                        SELECT message_id FROM message_checks WHERE account = lower(?2) AND kept = 1
+                      ))
+                      AND sender NOT IN (SELECT value FROM json_each(?4))
+                 )
+                 WHERE n <= ?5 ORDER BY id",
+            )?;
+            let rows = query.query_map(
+                params![
+                    channel.to_lowercase(),
+                    own,
+                    since.map(timestamp).unwrap_or_default(),
+                    serde_json::to_string(hidden).unwrap_or_default(),
+                    limit as i64,
+                ],
+                |r| Ok((r.get(0)?, user_msg(r.get(1)?, r.get(2)?, r.get(3)?))),
+            )?;
+            rows.collect::<rusqlite::Result<Vec<(i64, UserMsg)>>>()
+        })();
+
+        result.unwrap_or_else(|err| {
+            error!("Failed to read chat of {}: {}", channel, err);
+            Vec::new()
+        })
+    }
+
+    /// Store a generation with the chat it was generated from, returns its id.
+    pub fn record_generation(&self, generation: &Generation) -> Option<i64> {
+        let result = (|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO generations
+                 (account, channel, prompt, output, model, prompt_tokens, completion_tokens, latency_ms, outcome, created_at, cache_keys)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    generation.account,
+                    generation.channel.to_lowercase(),
+                    serde_json::to_string(&generation.prompt).unwrap_or_default(),
+                    generation.output,
+                    generation.model,
+                    generation.usage.prompt_tokens as i64,
+                    generation.usage.completion_tokens as i64,
+                    generation.latency_ms as i64,
+                    generation.outcome,
+                    timestamp(generation.created_at),
+                    serde_json::to_string(&generation.cache_keys).unwrap_or_default(),
+                ],
+            )?;
+            let id = tx.last_insert_rowid();
+            {
+                let mut link = tx.prepare_cached(
+                    "INSERT INTO generation_messages (generation_id, message_id) VALUES (?1, ?2)",
+                )?;
+                for message_id in &generation.message_ids {
+                    link.execute(params![id, message_id])?;
+                }
+            }
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(id)
+        })();
+
+        result
+            .map_err(|err| error!("Failed to store generation: {}", err))
+            .ok()
+    }
+
+    /// Set the outcome of the account's latest pending generation of `output`.
+    pub fn set_outcome(&self, account: &Account, output: &str, outcome: &str) {
+        let result = (|| {
+            let conn = self.conn.lock().unwrap();
+            let id: Option<i64> = conn
+                .query_row(
+                    "SELECT id FROM generations
+                     WHERE account = ?1 AND channel = ?2 AND output = ?3 AND outcome IS NULL
+                     ORDER BY id DESC LIMIT 1",
+                    params![account.account_name, account.channel.to_lowercase(), output],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            if let Some(id) = id {
+                conn.execute(
+                    "UPDATE generations SET outcome = ?1 WHERE id = ?2",
+                    params![outcome, id],
+                )?;
+            }
+            Ok::<_, rusqlite::Error>(())
+        })();
+        if let Err(err) = result {
+            error!("Failed to store outcome: {}", err);
+        }
+    }
+
+    /// Up to `limit` most recent generations of the account, newest first.
+    pub fn generations(&self, account_name: &str, limit: usize) -> Vec<Generation> {
+        let result = (|| {
+            let conn = self.conn.lock().unwrap();
+            let mut query = conn.prepare_cached(
+                "SELECT id, account, channel, prompt, output, model, prompt_tokens,
+                        completion_tokens, latency_ms, outcome, created_at, cache_keys,
+                        (SELECT json_group_array(message_id) FROM generation_messages
+                         WHERE generation_id = generations.id)
+                 FROM generations WHERE account = ?1 ORDER BY id DESC LIMIT ?2",
+            )?;
+            let rows = query.query_map(params![account_name, limit as i64], |r| {
+                let prompt: String = r.get(3)?;
+                let created_at: String = r.get(10)?;
+                let cache_keys: String = r.get(11)?;
+                let message_ids: String = r.get(12)?;
+                Ok(Generation {
+                    id: r.get(0)?,
+                    account: r.get(1)?,
+                    channel: r.get(2)?,
+                    prompt: serde_json::from_str(&prompt).unwrap_or_default(),
+                    output: r.get(4)?,
+                    model: r.get(5)?,
+                    usage: Usage {
+                        prompt_tokens: r.get::<_, i64>(6)? as u64,
+                        completion_tokens: r.get::<_, i64>(7)? as u64,
+                    },
+                    latency_ms: r.get::<_, i64>(8)? as u64,
+                    outcome: r.get(9)?,
+                    created_at: DateTime::parse_from_rfc3339(&created_at)
+                        .map(|t| t.with_timezone(&Utc))
+                        .unwrap_or_default(),
+                    message_ids: serde_json::from_str(&message_ids).unwrap_or_default(),
+                    cache_keys: serde_json::from_str(&cache_keys).unwrap_or_default(),
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<Generation>>>()
+        })();
+
+        result.unwrap_or_else(|err| {
+            error!("Failed to read generations of {}: {}", account_name, err);
+            Vec::new()
+        })
+    }
+}
+
+/// Store at the configured path, in memory if it can't be opened
+pub static STORE: Lazy<Store> = Lazy::new(|| {
+    let path = &CONFIG.get().store.path;
+    Store::open(path).unwrap_or_else(|err| {
+        error!(
+            "Failed to open store {}: {}, keeping chat in memory",
+            path, err
+        );
+        Store::open_in_memory().expect("in-memory SQLite")
+    })
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(sender: &str, text: &str) -> UserMsg {
+        UserMsg {
+            sender: sender.into(),
+            message: text.into(),
+            tags: HashMap::from([("id".to_string(), format!("{}-{}", sender, text))]),
+        }
+    }
+
+    fn texts(msgs: &[(i64, UserMsg)]) -> Vec<&str> {
+        msgs.iter().map(|(_, m)| m.message.as_str()).collect()
+    }
+
+    #[test]
+    fn migrations_apply_once() {
+        let path = std::env::temp_dir().join(format!("store-test-{}.db", std::process::id()));
+        let path = path.to_string_lossy();
+        let _ = fs::remove_file(path.as_ref());
+
+        for _ in 0..2 {
+            let store = Store::open(&path).unwrap();
+            let version: usize = store
+                .conn
+                .lock()
+                .unwrap()
+                .query_row("PRAGMA user_version", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(version, MIGRATIONS.len());
+        }
+        let _ = fs::remove_file(path.as_ref());
+    }
+
+    #[test]
+    fn checks_are_per_account() {
+        let store = Store::open_in_memory().unwrap();
+        store.record_messages(
+            "Chan",
+            &[msg("viewer", "hello"), msg("viewer", "bad")],
+            false,
+        );
+        // received again by the other account's connection
+        store.record_messages("chan", &[msg("viewer", "hello")], false);
+        store.record_messages("chan", &[msg("bota", "mine")], true);
+
+        let pending = store.pending_messages("chan", "botA", None);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            store.recent_messages("chan", "botA", 10, None, &[]).len(),
+            1
+        );
+
+        store.mark_checked("botA", &[(pending[0].0, true), (pending[1].0, false)]);
+        assert!(store.pending_messages("chan", "bota", None).is_empty());
+        assert_eq!(
+            texts(&store.recent_messages("chan", "botA", 10, None, &[])),
+            ["hello", "mine"]
+        );
+
+        // another account in the channel still has to check them
+        assert_eq!(store.pending_messages("chan", "botB", None).len(), 2);
+        assert_eq!(
+            texts(&store.recent_messages("chan", "botB", 10, None, &[])),
+            ["mine"]
+        );
+        store.mark_checked("botB", &[(pending[0].0, false), (pending[1].0, true)]);
+        assert_eq!(
+            texts(&store.recent_messages("chan", "botB", 10, None, &["bota".into()])),
+            ["bad"]
+        );
+    }
+
+    #[test]
+    fn recent_messages_limit_own_and_others_apart() {
+        let store = Store::open_in_memory().unwrap();
+        let chat: Vec<UserMsg> = (1..=5)
+            .map(|i| msg("viewer", &format!("chat {}", i)))
+            .collect();
+        store.record_messages("chan", &[msg("bot", "own 1"), msg("bot", "own 2")], true);
+        store.record_messages("chan", &chat, true);
+        store.record_messages("chan", &[msg("bot", "own 3")], true);
+
+        assert_eq!(
+            texts(&store.recent_messages("chan", "Bot", 2, None, &[])),
+            ["own 2", "chat 4", "chat 5", "own 3"]
+        );
+        assert!(store
+            .recent_messages(
+                "chan",
+                "bot",
+                2,
+                Some(Utc::now() + chrono::Duration::seconds(1)),
+                &[]
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn forget_viewer_deletes_what_was_generated_from_their_chat() {
+        let store = Store::open_in_memory().unwrap();
+        let mut viewer = msg("viewer", "i live in paris");
+        viewer.tags.insert("user-id".into(), "42".into());
+        store.record_messages("chan", &[viewer, msg("other", "i live in paris too")], true);
+        let chat = store.recent_messages("chan", "bot", 10, None, &[]);
+
+        let generation = |output: &str, message_ids: Vec<i64>| Generation {
+            id: 0,
+            account: "Bot".into(),
+            channel: "chan".into(),
+            prompt: vec![Message::new("user", "i live in paris")],
+            output: output.into(),
+            model: "mock".into(),
+            usage: Usage::default(),
+            latency_ms: 0,
+            outcome: None,
+            created_at: Utc::now(),
+            message_ids,
+            cache_keys: vec![format!("key {}", output)],
+        };
+        store.record_generation(&generation("paris is nice", vec![chat[0].0, chat[1].0]));
+        store.record_generation(&generation("welcome", vec![chat[1].0]));
+        store.record_messages(
+            "chan",
+            &[msg("bot", "paris is nice"), msg("bot", "welcome")],
+            true,
+        );
+
+        let forgotten = store.forget_viewer("42");
+        assert_eq!(forgotten.outputs, ["paris is nice"]);
+        assert_eq!(forgotten.cache_keys, ["key paris is nice"]);
+
+        let left = store.generations("Bot", 10);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].message_ids, [chat[1].0]);
+        assert_eq!(
+            texts(&store.recent_messages("chan", "bot", 10, None, &[])),
+            ["i live in paris too", "welcome"]
+        );
+        assert!(store.viewer("42").is_none());
+    }
+}
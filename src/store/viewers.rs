@@ -0,0 +1,198 @@
+use chrono::{DateTime, Utc};
+use log::error;
+use rusqlite::{params, OptionalExtension, Row};
+
+use super::{timestamp, Store};
+use crate::{twitch::UserMsg, viewers::ViewerMemory};
+
+const VIEWER_COLUMNS: &str =
+    "user_id, login, display_name, notes, message_count, summarized_count, last_seen";
+
+fn viewer_from_row(r: &Row) -> rusqlite::Result<ViewerMemory> {
+    let message_count: i64 = r.get(4)?;
+    let summarized_count: i64 = r.get(5)?;
+    let last_seen: String = r.get(6)?;
+    Ok(ViewerMemory {
+        user_id: r.get(0)?,
+        login: r.get(1)?,
+        display_name: r.get(2)?,
+        notes: r.get(3)?,
+        message_count: message_count as u64,
+        last_seen: DateTime::parse_from_rfc3339(&last_seen)
+            .map(|t| t.with_timezone(&Utc))
+            .ok(),
+        unsummarized: (message_count - summarized_count).max(0) as u64,
+    })
+}
+
+/// What [`Store::forget_viewer`] deleted
+#[derive(Debug, Clone, Default)]
+pub struct Forgotten {
+    /// outputs of the deleted generations
+    pub outputs: Vec<String>,
+    /// response cache entries of the deleted generations
+    pub cache_keys: Vec<String>,
+}
+
+impl Store {
+    /// Count messages of viewers and update their names, messages without `user-id` are skipped.
+    pub fn see_viewers(&self, msgs: &[UserMsg]) {
+        let now = timestamp(Utc::now());
+        let result = (|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            {
+                let mut upsert = tx.prepare_cached(
+                    "INSERT INTO viewers (user_id, login, display_name, message_count, first_seen, last_seen)
+                     VALUES (?1, ?2, ?3, 1, ?4, ?4)
+                     ON CONFLICT (user_id) DO UPDATE SET
+                        login = excluded.login,
+                        display_name = excluded.display_name,
+                        message_count = message_count + 1,
+                        last_seen = excluded.last_seen",
+                )?;
+                for msg in msgs {
+                    let Some(user_id) = msg.tags.get("user-id") else {
+                        continue;
+                    };
+                    let display_name = msg
+                        .tags
+                        .get("display-name")
+                        .filter(|d| !d.is_empty())
+                        .unwrap_or(&msg.sender);
+                    upsert.execute(params![user_id, msg.sender, display_name, now])?;
+                }
+            }
+            tx.commit()
+        })();
+        if let Err(err) = result {
+            error!("Failed to store viewers: {}", err);
+        }
+    }
+
+    pub fn viewer(&self, user_id: &str) -> Option<ViewerMemory> {
+        let result = {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row(
+                &format!("SELECT {} FROM viewers WHERE user_id = ?1", VIEWER_COLUMNS),
+                [user_id],
+                viewer_from_row,
+            )
+            .optional()
+        };
+
+        result.unwrap_or_else(|err| {
+            error!("Failed to read viewer {}: {}", user_id, err);
+            None
+        })
+    }
+
+    /// Up to `limit` kept messages of the viewer not summarized into the notes yet, oldest first
+    pub fn unsummarized_messages(&self, viewer: &ViewerMemory, limit: usize) -> Vec<String> {
+        let result = (|| {
+            let conn = self.conn.lock().unwrap();
+            let mut query = conn.prepare_cached(
+                "SELECT text FROM messages
+                 WHERE json_extract(tags, '$.\"user-id\"') = ?1
+                   AND (kept = 1 OR id IN (SELECT message_id FROM message_checks WHERE kept = 1))
+                 ORDER BY id DESC LIMIT ?2",
+            )?;
+            let rows = query.query_map(
+                params![
+                    viewer.user_id,
+                    limit.min(viewer.unsummarized as usize) as i64
+                ],
+                |r| r.get(0),
+            )?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })();
+
+        match result {
+            Ok(mut texts) => {
+                texts.reverse();
+                texts
+            }
+            Err(err) => {
+                error!("Failed to read messages of {}: {}", viewer.login, err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Replace the notes, marking every message so far as summarized.
+    pub fn set_viewer_notes(&self, user_id: &str, notes: &str) {
+        let result = self.conn.lock().unwrap().execute(
+            "UPDATE viewers SET notes = ?1, summarized_count = message_count WHERE user_id = ?2",
+            params![notes, user_id],
+        );
+        if let Err(err) = result {
+            error!("Failed to store notes of {}: {}", user_id, err);
+        }
+    }
+
+    /// Delete the viewer, every message they sent and the generations generated from one of
+    /// them, along with the bot's messages of those generations.
+    pub fn forget_viewer(&self, user_id: &str) -> Forgotten {
+        let result = (|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let mut forgotten = Forgotten::default();
+            {
+                let mut query = tx.prepare_cached(
+                    "DELETE FROM generations WHERE id IN (
+                        SELECT generation_id FROM generation_messages WHERE message_id IN (
+                            SELECT id FROM messages WHERE json_extract(tags, '$.\"user-id\"') = ?1
+                        )
+                     )
+                     RETURNING account, channel, output, cache_keys",
+                )?;
+                let generated = query
+                    .query_map([user_id], |r| {
+                        Ok((
+                            r.get::<_, String>(0)?,
+                            r.get::<_, String>(1)?,
+                            r.get::<_, String>(2)?,
+                            r.get::<_, String>(3)?,
+                        ))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                let mut delete = tx.prepare_cached(
+                    "DELETE FROM messages
+                     WHERE channel = ?1 AND lower(sender) = lower(?2) AND text = ?3",
+                )?;
+                for (account, channel, output, cache_keys) in generated {
+                    if !output.is_empty() {
+                        delete.execute(params![channel, account, output])?;
+                    }
+                    forgotten.outputs.push(output);
+                    forgotten.cache_keys.extend(
+                        serde_json::from_str::<Vec<String>>(&cache_keys).unwrap_or_default(),
+                    );
+                }
+            }
+            tx.execute("DELETE FROM viewers WHERE user_id = ?1", [user_id])?;
+            tx.execute(
+                "DELETE FROM messages WHERE json_extract(tags, '$.\"user-id\"') = ?1",
+                [user_id],
+            )?;
+            tx.execute(
+                "DELETE FROM generation_messages
+                 WHERE generation_id NOT IN (SELECT id FROM generations)
+                    OR message_id NOT IN (SELECT id FROM messages)",
+                [],
+            )?;
+            tx.execute(
+                "DELETE FROM message_checks WHERE message_id NOT IN (SELECT id FROM messages)",
+                [],
+            )?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(forgotten)
+        })();
+
+        result.unwrap_or_else(|err| {
+            error!("Failed to forget viewer {}: {}", user_id, err);
+            Forgotten::default()
+        })
+    }
+}
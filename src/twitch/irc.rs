@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+/// IRC line with IRCv3 tags, as Twitch sends them once `twitch.tv/tags` is requested
+#[derive(Debug, Clone, Default)]
+pub struct IrcMessage {
+    pub tags: HashMap<String, String>,
+    /// nick of the prefix, the sender's login for PRIVMSG
+    pub nick: Option<String>,
+    pub command: String,
+    /// parameters, the trailing one last
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+
+    /// Channel without `#`
+    pub fn channel(&self) -> Option<&str> {
+        self.params.iter().find_map(|p| p.strip_prefix('#'))
+    }
+
+    /// Last parameter, the message text for PRIVMSG and NOTICE
+    pub fn trailing(&self) -> Option<&str> {
+        self.params.last().map(String::as_str)
+    }
+}
+
+/// Parse one line, e.g. `@id=1;login=bob :bob!bob@bob.tmi.twitch.tv PRIVMSG #chan :hi`
+pub fn parse(line: &str) -> Option<IrcMessage> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    let mut msg = IrcMessage::default();
+
+    if let Some(tagged) = rest.strip_prefix('@') {
+        let (tags, after) = tagged.split_once(' ')?;
+        msg.tags = tags
+            .split(';')
+            .map(|tag| match tag.split_once('=') {
+                Some((k, v)) => (k.to_string(), unescape_tag(v)),
+                None => (tag.to_string(), String::new()),
+            })
+            .collect();
+        rest = after.trim_start();
+    }
+
+    if let Some(prefixed) = rest.strip_prefix(':') {
+        let (prefix, after) = prefixed.split_once(' ')?;
+        msg.nick = Some(prefix.split('!').next().unwrap_or(prefix).to_string());
+        rest = after.trim_start();
+    }
+
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split_whitespace();
+    msg.command = words.next()?.to_string();
+    msg.params = words.map(str::to_string).collect();
+    if let Some(trailing) = trailing {
+        msg.params.push(trailing.to_string());
+    }
+
+    Some(msg)
+}
+
+/// Tag values escape `;`, spaces, backslashes and line breaks
+fn unescape_tag(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privmsg_quoting_commands() {
+        let msg = parse(
+            "@display-name=Bob;id=1 :bob!bob@bob.tmi.twitch.tv PRIVMSG #chan :did you NOTICE that :)",
+        )
+        .unwrap();
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.nick.as_deref(), Some("bob"));
+        assert_eq!(msg.channel(), Some("chan"));
+        assert_eq!(msg.trailing(), Some("did you NOTICE that :)"));
+        assert_eq!(msg.tag("display-name"), Some("Bob"));
+    }
+
+    #[test]
+    fn commands_and_tags() {
+        let msg = parse(
+            "@msg-id=msg_duplicate :tmi.twitch.tv NOTICE #chan :Your message is identical\r\n",
+        )
+        .unwrap();
+        assert_eq!(msg.command, "NOTICE");
+        assert_eq!(msg.tag("msg-id"), Some("msg_duplicate"));
+
+        let msg =
+            parse("@ban-duration=600;target-user-id=2 :tmi.twitch.tv CLEARCHAT #chan :chatbot")
+                .unwrap();
+        assert_eq!(msg.command, "CLEARCHAT");
+        assert_eq!(msg.trailing(), Some("chatbot"));
+
+        let msg = parse(r"@system-msg=a\sb\:c :tmi.twitch.tv USERNOTICE #chan").unwrap();
+        assert_eq!(msg.tag("system-msg"), Some("a b;c"));
+
+        assert_eq!(parse("PING :tmi.twitch.tv").unwrap().command, "PING");
+        assert!(parse("").is_none());
+    }
+}
@@ -1,11 +1,12 @@
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
+use std::{collections::HashMap, time::Duration};
+
 use futures_util::{SinkExt, StreamExt};
-use log::trace;
-use once_cell::sync::Lazy;
-use regex::Regex;
+use log::{debug, trace};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
 use tokio_tungstenite::{
     client_async_tls_with_config, connect_async,
     tungstenite::{self, Message},
@@ -13,20 +14,68 @@ use tokio_tungstenite::{
 };
 use url::Url;
 
-use crate::config::{utils::get_account_names, Account, ProxyConfig, CONFIG};
+use crate::{
+    config::{utils::get_account_names, Account, ProxyConfig, CONFIG},
+    kill_switch, sanctions,
+};
+use room_state::SendDecision;
 
+pub mod irc;
+pub mod room_state;
+pub mod sent_log;
 pub mod utils;
 
-// Regex of PRIVMSG
-static MSG_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"^:(?P<sender>[^! ]+)![^ ]+@[^ ]+\.tmi\.twitch\.tv PRIVMSG #[^ ]+ :(?P<msg>.+)$")
-        .unwrap()
-});
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct UserMsg {
     pub sender: String,
     pub message: String,
+    /// IRCv3 tags of the PRIVMSG, e.g. `user-id`, `badges`, `id`
+    pub tags: HashMap<String, String>,
+}
+
+impl UserMsg {
+    /// Sent by the broadcaster or a moderator of the channel
+    pub fn is_moderator(&self) -> bool {
+        let badges = self.tags.get("badges").map_or("", String::as_str);
+        self.tags.get("mod").is_some_and(|m| m == "1")
+            || badges
+                .split(',')
+                .any(|b| b.starts_with("broadcaster/") || b.starts_with("moderator/"))
+    }
+}
+
+/// NOTICE `msg-id`s refusing a message the bot sent
+const SEND_REJECTIONS: &[&str] = &[
+    "msg_bad_characters",
+    "msg_banned",
+    "msg_channel_blocked",
+    "msg_channel_suspended",
+    "msg_duplicate",
+    "msg_emoteonly",
+    "msg_followersonly",
+    "msg_followersonly_followed",
+    "msg_followersonly_zero",
+    "msg_r9k",
+    "msg_ratelimit",
+    "msg_rejected",
+    "msg_rejected_mandatory",
+    "msg_requires_verified_phone_number",
+    "msg_slowmode",
+    "msg_subsonly",
+    "msg_suspended",
+    "msg_timedout",
+    "msg_verified_email",
+];
+
+/// What chat did with a message of the bot while it was watched
+#[derive(Debug, Clone, Default)]
+pub struct Reaction {
+    /// `msg-id` of a NOTICE, e.g. msg_duplicate or msg_ratelimit
+    pub rejected: Option<String>,
+    /// messages replying to or mentioning the bot
+    pub replies: u32,
+    /// removed by a moderator
+    pub deleted: bool,
 }
 
 #[derive(Clone)]
@@ -48,6 +97,12 @@ pub enum TwitchError {
     ProxyResponseIncomplete,
     #[error("Invalid proxy configuration: {0}")]
     InvalidProxyConfig(&'static str),
+    #[error("Channel restricted: {0}")]
+    Restricted(String),
+    #[error("Not sending: {0}")]
+    Killed(String),
+    #[error("Suspended: {0}")]
+    Suspended(String),
 }
 
 pub struct Twitch<'a> {
@@ -62,12 +117,18 @@ impl<'a> Twitch<'a> {
     pub async fn connect_to_chat(
         &self,
     ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, TwitchError> {
-        let req = format!("wss://{}:443", CONFIG.twitch.host);
+        let req = format!("wss://{}:443", CONFIG.get().twitch.host);
         let (mut ws, _resp) = match &self.account.proxy {
             Some(proxy) => connect_via_proxy(&req, proxy).await?,
             None => connect_async(req.as_str()).await?,
         };
 
+        // tags carry message ids and NOTICE reasons, commands adds CLEARMSG and friends
+        ws.send(Message::Text(
+            "CAP REQ :twitch.tv/tags twitch.tv/commands\r\n".into(),
+        ))
+        .await?;
+
         ws.send(Message::Text(
             format!("PASS {}\r\n", self.account.oauth).into(),
         ))
@@ -91,19 +152,77 @@ impl<'a> Twitch<'a> {
             format!("JOIN #{}\r\n", self.account.channel).into(),
         ))
         .await?;
+        self.wait_for_room_state(&mut ws).await?;
 
         Ok(ws)
     }
 
+    /// Read the USERSTATE and ROOMSTATE Twitch sends after JOIN, so sends see the current modes.
+    ///
+    /// Gives up after a few seconds and keeps the last known state.
+    async fn wait_for_room_state(
+        &self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> Result<(), TwitchError> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while let Ok(Some(msg)) = timeout_at(deadline, ws.next()).await {
+            let msg = msg?;
+            let Ok(text) = msg.to_text() else {
+                continue;
+            };
+            let mut joined = false;
+            for line in text.lines() {
+                let Some(irc) = irc::parse(line) else {
+                    continue;
+                };
+                match irc.command.as_str() {
+                    "PING" => ws.send(Message::Text("PONG\r\n".into())).await?,
+                    "ROOMSTATE" => {
+                        room_state::update(self.account, &irc);
+                        joined = true;
+                    }
+                    "USERSTATE" => room_state::update(self.account, &irc),
+                    _ => {}
+                }
+            }
+            if joined {
+                return Ok(());
+            }
+        }
+
+        debug!("No ROOMSTATE from {}", self.account.channel);
+        Ok(())
+    }
+
     pub async fn send_chat(
         &self,
-        mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
         text: String,
     ) -> Result<(), TwitchError> {
+        if let Some(reason) = kill_switch::killed_reason(self.account) {
+            return Err(TwitchError::Killed(reason));
+        }
+        // a timed out or banned account only gets NOTICEs back
+        if let Some(reason) = sanctions::suspended_reason(self.account) {
+            return Err(TwitchError::Suspended(reason));
+        }
+        match room_state::check_send(self.account) {
+            SendDecision::Send => {}
+            // waiting here would hold up every other account
+            SendDecision::Wait(wait) => {
+                return Err(TwitchError::Restricted(format!(
+                    "slow mode, next message allowed in {}s",
+                    wait.as_secs_f32().ceil()
+                )))
+            }
+            SendDecision::Skip(reason) => return Err(TwitchError::Restricted(reason)),
+        }
+
         ws.send(Message::Text(
             format!("PRIVMSG #{} :{}", self.account.channel, text).into(),
         ))
         .await?;
+        room_state::mark_sent(self.account);
 
         Ok(())
     }
@@ -121,22 +240,32 @@ impl<'a> Twitch<'a> {
         while let Some(msg) = ws.next().await {
             let msg = msg?;
             if let Ok(text) = msg.to_text() {
-                // send pong
-                if text.starts_with("PING") {
-                    ws.send(Message::Text("PONG\r\n".into())).await?;
-                // PRIVMSG
-                } else {
-                    if let Some((sender, msg)) = parse_msg(text.trim()) {
-                        trace!("{}: {}", sender, msg);
-
-                        if get_account_names().contains(&sender.as_str()) {
-                            continue;
+                // a frame may hold several lines
+                for line in text.lines() {
+                    let Some(irc) = irc::parse(line) else {
+                        continue;
+                    };
+                    match irc.command.as_str() {
+                        "PING" => ws.send(Message::Text("PONG\r\n".into())).await?,
+                        // modes changed while reading
+                        "ROOMSTATE" | "USERSTATE" => room_state::update(self.account, &irc),
+                        // moderation aimed at the bot
+                        "CLEARCHAT" | "CLEARMSG" | "NOTICE" => {
+                            sanctions::observe(self.account, &irc)
                         }
+                        "PRIVMSG" => {
+                            let Some(user_msg) = user_msg(irc) else {
+                                continue;
+                            };
+                            trace!("{}: {}", user_msg.sender, user_msg.message);
+
+                            if get_account_names().contains(&user_msg.sender) {
+                                continue;
+                            }
 
-                        msg_history.push(UserMsg {
-                            sender: sender,
-                            message: msg,
-                        });
+                            msg_history.push(user_msg);
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -148,6 +277,57 @@ impl<'a> Twitch<'a> {
 
         Ok(msg_history)
     }
+
+    /// Watch chat for `window` after `sent` went out and collect the reaction to it.
+    pub async fn observe(
+        &self,
+        mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+        sent: &str,
+        window: Duration,
+    ) -> Result<Reaction, TwitchError> {
+        let deadline = Instant::now() + window;
+        let mut reaction = Reaction::default();
+        let name = self.account.account_name.to_lowercase();
+
+        while let Ok(Some(msg)) = timeout_at(deadline, ws.next()).await {
+            let msg = msg?;
+            let Ok(text) = msg.to_text() else {
+                continue;
+            };
+            for line in text.lines() {
+                let Some(irc) = irc::parse(line) else {
+                    continue;
+                };
+                sanctions::observe(self.account, &irc);
+                match irc.command.as_str() {
+                    "PING" => ws.send(Message::Text("PONG\r\n".into())).await?,
+                    "NOTICE" => {
+                        let msg_id = irc.tag("msg-id").unwrap_or_default();
+                        debug!(
+                            "{} got NOTICE {}: {}",
+                            self.account.channel,
+                            msg_id,
+                            irc.trailing().unwrap_or_default()
+                        );
+                        if SEND_REJECTIONS.contains(&msg_id) {
+                            reaction.rejected = Some(msg_id.to_string());
+                        }
+                    }
+                    "CLEARMSG"
+                        if irc.tag("login") == Some(name.as_str())
+                            && irc.trailing() == Some(sent) =>
+                    {
+                        reaction.deleted = true;
+                    }
+                    "PRIVMSG" if is_reply_to(&irc, &name) => reaction.replies += 1,
+                    "ROOMSTATE" | "USERSTATE" => room_state::update(self.account, &irc),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(reaction)
+    }
 }
 
 /// Make connection using proxy
@@ -174,7 +354,7 @@ async fn connect_via_proxy(
     let mut stream = TcpStream::connect((host, port)).await?;
 
     // connection to Twitch IRC
-    let twitch_irc = format!("{}:443", CONFIG.twitch.host);
+    let twitch_irc = format!("{}:443", CONFIG.get().twitch.host);
     // craft CONNECT request
     let mut connect_request = format!(
         "CONNECT {} HTTP/1.1\r\nHost: {}\r\n",
@@ -183,7 +363,7 @@ async fn connect_via_proxy(
 
     // add credentials if username and password available
     if let (Some(username), Some(password)) = (&proxy.username, &proxy.password) {
-        let auth_value = build_proxy_authorization(&username, &password);
+        let auth_value = build_proxy_authorization(username, password);
         connect_request.push_str(&format!("Proxy-Authorization: {}\r\n", auth_value));
     }
 
@@ -233,12 +413,17 @@ fn build_proxy_authorization(username: &str, password: &str) -> String {
     format!("Basic {}", BASE64_STANDARD.encode(credentials))
 }
 
-/// Parse PRIVMSG and return (Sender, Message)
-fn parse_msg(line: &str) -> Option<(String, String)> {
-    let captures = MSG_RE.captures(line)?;
-
-    let sender = captures.name("sender")?.as_str().to_string();
-    let msg = captures.name("msg")?.as_str().to_string();
+/// Reply thread on a message of `name`, or an @mention of it
+fn is_reply_to(msg: &irc::IrcMessage, name: &str) -> bool {
+    let text = msg.trailing().unwrap_or_default().to_lowercase();
+    msg.tag("reply-parent-user-login") == Some(name) || text.contains(&format!("@{}", name))
+}
 
-    Some((sender, msg))
+/// Sender, message and tags of a PRIVMSG
+fn user_msg(msg: irc::IrcMessage) -> Option<UserMsg> {
+    Some(UserMsg {
+        message: msg.trailing()?.to_string(),
+        sender: msg.nick?,
+        tags: msg.tags,
+    })
 }
@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use once_cell::sync::Lazy;
+
+use crate::{
+    config::{channel::channel_key, Account},
+    twitch::irc::IrcMessage,
+};
+
+/// Chat restrictions of a channel, from ROOMSTATE
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomState {
+    pub emote_only: bool,
+    pub subs_only: bool,
+    /// minutes an account must have followed, None when off
+    pub followers_only: Option<u64>,
+    /// seconds between messages of one user, 0 when off
+    pub slow: u64,
+    /// unique chat, identical messages are rejected
+    pub r9k: bool,
+}
+
+/// What the bot account is in a channel, from USERSTATE
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserState {
+    /// moderators and the broadcaster are exempt from every restriction
+    pub moderator: bool,
+    pub subscriber: bool,
+}
+
+/// Whether a message may go out now
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendDecision {
+    Send,
+    /// slow mode, the next message is allowed after waiting
+    Wait(Duration),
+    /// the channel's mode rejects the message
+    Skip(String),
+}
+
+// channel, current restrictions
+static ROOMS: Lazy<Mutex<HashMap<String, RoomState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// account_name:channel, badges of the bot
+static USERS: Lazy<Mutex<HashMap<String, UserState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// account_name:channel, when the bot last sent anything
+static LAST_SENT: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Update the tracked state from a ROOMSTATE or USERSTATE line.
+///
+/// ROOMSTATE after JOIN carries every tag, later ones only what changed.
+pub fn update(account: &Account, msg: &IrcMessage) {
+    let Some(channel) = msg.channel() else {
+        return;
+    };
+
+    match msg.command.as_str() {
+        "ROOMSTATE" => {
+            let mut rooms = ROOMS.lock().unwrap();
+            let room = rooms.entry(channel.to_lowercase()).or_default();
+            if let Some(v) = msg.tag("emote-only") {
+                room.emote_only = v == "1";
+            }
+            if let Some(v) = msg.tag("subs-only") {
+                room.subs_only = v == "1";
+            }
+            if let Some(v) = msg.tag("followers-only") {
+                // -1 is off, 0 or more is the required follow age in minutes
+                room.followers_only = v.parse::<u64>().ok();
+            }
+            if let Some(v) = msg.tag("slow") {
+                room.slow = v.parse().unwrap_or_default();
+            }
+            if let Some(v) = msg.tag("r9k") {
+                room.r9k = v == "1";
+            }
+            debug!("ROOMSTATE {}: {:?}", channel, room);
+        }
+        "USERSTATE" => {
+            let badges = msg.tag("badges").unwrap_or_default();
+            let has_badge = |name: &str| badges.split(',').any(|b| b.starts_with(name));
+            let state = UserState {
+                moderator: msg.tag("mod") == Some("1")
+                    || has_badge("broadcaster/")
+                    || has_badge("moderator/"),
+                subscriber: msg.tag("subscriber") == Some("1") || has_badge("subscriber/"),
+            };
+            USERS.lock().unwrap().insert(channel_key(account), state);
+        }
+        _ => {}
+    }
+}
+
+pub fn room_state(channel: &str) -> Option<RoomState> {
+    ROOMS.lock().unwrap().get(&channel.to_lowercase()).cloned()
+}
+
+/// Restriction keeping the account from posting at all, None if there is none.
+///
+/// Slow mode only delays and doesn't count.
+pub fn restriction(account: &Account) -> Option<String> {
+    let room = room_state(&account.channel)?;
+    let user = USERS
+        .lock()
+        .unwrap()
+        .get(&channel_key(account))
+        .cloned()
+        .unwrap_or_default();
+    if user.moderator {
+        return None;
+    }
+
+    if room.emote_only {
+        return Some("emote-only mode".into());
+    }
+    if room.subs_only && !user.subscriber {
+        return Some("subscribers-only mode".into());
+    }
+    if let Some(minutes) = room.followers_only {
+        if !account.follows_channel {
+            return Some(format!("followers-only mode ({} minutes)", minutes));
+        }
+    }
+
+    None
+}
+
+/// Decide whether a message may be sent now.
+pub fn check_send(account: &Account) -> SendDecision {
+    if let Some(reason) = restriction(account) {
+        return SendDecision::Skip(reason);
+    }
+
+    let slow = slow_delay(account);
+    if slow == 0 {
+        return SendDecision::Send;
+    }
+
+    let last = LAST_SENT
+        .lock()
+        .unwrap()
+        .get(&channel_key(account))
+        .copied();
+    match last.map(|at| Duration::from_secs(slow).saturating_sub(at.elapsed())) {
+        Some(wait) if !wait.is_zero() => SendDecision::Wait(wait),
+        _ => SendDecision::Send,
+    }
+}
+
+fn is_moderator(account: &Account) -> bool {
+    USERS
+        .lock()
+        .unwrap()
+        .get(&channel_key(account))
+        .is_some_and(|u| u.moderator)
+}
+
+/// Seconds slow mode requires between the account's messages, 0 if it doesn't apply
+pub fn slow_delay(account: &Account) -> u64 {
+    if is_moderator(account) {
+        return 0;
+    }
+
+    room_state(&account.channel).map_or(0, |r| r.slow)
+}
+
+/// Unique chat (r9k) rejects the account's messages repeating recent ones
+pub fn unique_chat(account: &Account) -> bool {
+    !is_moderator(account) && room_state(&account.channel).is_some_and(|r| r.r9k)
+}
+
+pub fn mark_sent(account: &Account) {
+    LAST_SENT
+        .lock()
+        .unwrap()
+        .insert(channel_key(account), Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitch::irc;
+
+    /// Every test uses its own channel, the state is global
+    fn account(channel: &str, follows_channel: bool) -> Account {
+        serde_yml::from_str(&format!(
+            "oauth: x\naccount_name: chatbot\nchannel: {}\ninstruction: x\ngpt_model: mock\n\
+             operating_mode: ALWAYS\ninterval: 1\ntimeout: 1\nchat_history_size: 4\n\
+             follows_channel: {}",
+            channel, follows_channel
+        ))
+        .unwrap()
+    }
+
+    fn receive(account: &Account, tags: &str, command: &str) {
+        let line = format!("@{} :tmi.twitch.tv {} #{}", tags, command, account.channel);
+        update(account, &irc::parse(&line).unwrap());
+    }
+
+    #[test]
+    fn unknown_room_allows_sending() {
+        let account = account("room_unknown", false);
+        assert_eq!(restriction(&account), None);
+        assert_eq!(check_send(&account), SendDecision::Send);
+        assert!(!unique_chat(&account));
+    }
+
+    #[test]
+    fn modes() {
+        let account = account("room_modes", false);
+        receive(
+            &account,
+            "emote-only=1;followers-only=-1;r9k=0;slow=0;subs-only=0",
+            "ROOMSTATE",
+        );
+        assert_eq!(
+            check_send(&account),
+            SendDecision::Skip("emote-only mode".into())
+        );
+
+        // later ROOMSTATEs only carry what changed
+        receive(&account, "emote-only=0;subs-only=1", "ROOMSTATE");
+        assert_eq!(
+            restriction(&account).as_deref(),
+            Some("subscribers-only mode")
+        );
+        receive(&account, "badges=subscriber/12;mod=0", "USERSTATE");
+        assert_eq!(restriction(&account), None);
+
+        receive(&account, "followers-only=10", "ROOMSTATE");
+        assert_eq!(
+            restriction(&account).as_deref(),
+            Some("followers-only mode (10 minutes)")
+        );
+        let follower = Account {
+            follows_channel: true,
+            ..account.clone()
+        };
+        assert_eq!(restriction(&follower), None);
+
+        receive(&account, "r9k=1", "ROOMSTATE");
+        assert!(unique_chat(&account));
+    }
+
+    #[test]
+    fn moderators_are_exempt() {
+        let account = account("room_moderated", false);
+        receive(&account, "emote-only=1;r9k=1;slow=30", "ROOMSTATE");
+        receive(&account, "badges=moderator/1;mod=1", "USERSTATE");
+        assert_eq!(restriction(&account), None);
+        assert_eq!(slow_delay(&account), 0);
+        assert!(!unique_chat(&account));
+        mark_sent(&account);
+        assert_eq!(check_send(&account), SendDecision::Send);
+    }
+
+    #[test]
+    fn slow_mode_waits_after_sending() {
+        let account = account("room_slow", false);
+        receive(&account, "slow=30", "ROOMSTATE");
+        assert_eq!(slow_delay(&account), 30);
+        assert_eq!(check_send(&account), SendDecision::Send);
+
+        mark_sent(&account);
+        match check_send(&account) {
+            SendDecision::Wait(wait) => {
+                assert!(wait <= Duration::from_secs(30) && wait > Duration::from_secs(29))
+            }
+            other => panic!("expected to wait, got {:?}", other),
+        }
+    }
+}
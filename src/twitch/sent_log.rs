@@ -0,0 +1,57 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::config::{channel::channel_key, Account};
+
+/// Messages kept per account and channel
+const CAPACITY: usize = 20;
+
+/// Message the bot sent
+#[derive(Debug, Clone)]
+pub struct SentMsg {
+    pub text: String,
+    pub at: Instant,
+}
+
+// account_name:channel, recent messages oldest first
+static SENT: Lazy<Mutex<HashMap<String, VecDeque<SentMsg>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn record_sent(account: &Account, text: &str) {
+    let mut sent = SENT.lock().unwrap();
+    let log = sent.entry(channel_key(account)).or_default();
+    log.push_back(SentMsg {
+        text: text.to_string(),
+        at: Instant::now(),
+    });
+    while log.len() > CAPACITY {
+        log.pop_front();
+    }
+}
+
+/// Messages any of our accounts sent to `channel` within `window`, oldest first.
+pub fn sent_to_channel(channel: &str, window: Duration) -> Vec<SentMsg> {
+    let sent = SENT.lock().unwrap();
+    let mut found: Vec<SentMsg> = sent
+        .iter()
+        .filter(|(key, _)| key.split_once(':').is_some_and(|(_, c)| c == channel))
+        .flat_map(|(_, log)| log.iter())
+        .filter(|m| m.at.elapsed() <= window)
+        .cloned()
+        .collect();
+    found.sort_by_key(|m| m.at);
+    found
+}
+
+/// Drop messages with any of `texts`.
+pub fn forget(texts: &[String]) {
+    let mut sent = SENT.lock().unwrap();
+    for log in sent.values_mut() {
+        log.retain(|m| !texts.contains(&m.text));
+    }
+}
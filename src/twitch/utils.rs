@@ -4,7 +4,7 @@ use serde_json::{json, Value};
 
 use crate::config::Account;
 
-pub async fn is_online(account: &Account) -> bool {
+pub async fn is_online(account: &Account, client: &reqwest::Client) -> bool {
     let endpoint = "https://gql.twitch.tv/gql";
 
     let payload = json!(
@@ -32,7 +32,6 @@ pub async fn is_online(account: &Account) -> bool {
 
     let payload_string = serde_json::to_string(&payload).unwrap();
 
-    let client = reqwest::Client::new();
     let resp = match client
         .post(endpoint)
         .body(payload_string)
@@ -57,3 +56,45 @@ pub async fn is_online(account: &Account) -> bool {
 
     false
 }
+
+/// Broadcast settings and live status of a channel
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub title: Option<String>,
+    pub game: Option<String>,
+    /// None while offline
+    pub started_at: Option<String>,
+}
+
+pub async fn get_stream_info(channel: &str, client: &reqwest::Client) -> Option<StreamInfo> {
+    let endpoint = "https://gql.twitch.tv/gql";
+
+    let payload = json!({
+        "query": "query($login: String!) { user(login: $login) { broadcastSettings { title game { displayName } } stream { createdAt } } }",
+        "variables": { "login": channel }
+    });
+
+    let resp = match client
+        .post(endpoint)
+        .header("Client-id", "kimne78kx3ncx6brgo4mv6wki5h1ko")
+        .json(&payload)
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(err) => {
+            error!("{}", err);
+            return None;
+        }
+    };
+
+    let json = resp.json::<Value>().await.ok()?;
+    let user = json.pointer("/data/user")?;
+    let text = |ptr: &str| user.pointer(ptr).and_then(|v| v.as_str()).map(String::from);
+
+    Some(StreamInfo {
+        title: text("/broadcastSettings/title"),
+        game: text("/broadcastSettings/game/displayName"),
+        started_at: text("/stream/createdAt"),
+    })
+}
@@ -0,0 +1,206 @@
+use std::sync::Mutex;
+
+use chrono::{Datelike, NaiveDate, Utc};
+use log::error;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    chat_model::core::Usage,
+    config::{Account, UsageConfig, CONFIG},
+    persist::{read_json, write_json},
+};
+
+#[derive(Error, Debug)]
+pub enum BudgetError {
+    #[error("{account} spent ${spent:.4} today, daily budget is ${limit:.4}")]
+    Daily {
+        account: String,
+        spent: f64,
+        limit: f64,
+    },
+    #[error("{account} spent ${spent:.4} this month, monthly budget is ${limit:.4}")]
+    Monthly {
+        account: String,
+        spent: f64,
+        limit: f64,
+    },
+}
+
+/// Usage of one account and model on one day (UTC)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageEntry {
+    pub account: String,
+    pub model: String,
+    pub day: NaiveDate,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+}
+
+/// Filter for [`UsageLedger::totals`], unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct UsageQuery {
+    pub account: Option<String>,
+    pub model: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl UsageQuery {
+    fn matches(&self, entry: &UsageEntry) -> bool {
+        self.account.as_ref().is_none_or(|a| *a == entry.account)
+            && self.model.as_ref().is_none_or(|m| *m == entry.model)
+            && self.from.is_none_or(|d| entry.day >= d)
+            && self.to.is_none_or(|d| entry.day <= d)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+}
+
+/// Token and cost ledger persisted to a JSON file
+pub struct UsageLedger {
+    config: UsageConfig,
+    entries: Mutex<Vec<UsageEntry>>,
+}
+
+impl UsageLedger {
+    pub fn open(config: UsageConfig) -> Self {
+        let entries: Vec<UsageEntry> = read_json(&config.path);
+        Self {
+            config,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Estimated cost in USD, zero for models without a price.
+    ///
+    /// Dated snapshots (gpt-5-nano-2025-08-07) use the price of the longest matching prefix.
+    pub fn estimate_cost(&self, model: &str, usage: &Usage) -> f64 {
+        self.config
+            .prices
+            .get(model)
+            .or_else(|| {
+                self.config
+                    .prices
+                    .iter()
+                    .filter(|(name, _)| model.starts_with(name.as_str()))
+                    .max_by_key(|(name, _)| name.len())
+                    .map(|(_, price)| price)
+            })
+            .map(|p| {
+                (usage.prompt_tokens as f64 * p.prompt
+                    + usage.completion_tokens as f64 * p.completion)
+                    / 1_000_000.0
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn record(&self, account: &str, model: &str, usage: &Usage) {
+        let cost = self.estimate_cost(model, usage);
+        let today = Utc::now().date_naive();
+
+        let mut entries = self.entries.lock().unwrap();
+        let idx = match entries
+            .iter()
+            .position(|e| e.account == account && e.model == model && e.day == today)
+        {
+            Some(idx) => idx,
+            None => {
+                entries.push(UsageEntry {
+                    account: account.to_string(),
+                    model: model.to_string(),
+                    day: today,
+                    requests: 0,
+                    prompt_tokens: 0,
+                    completion_tokens: 0,
+                    cost_usd: 0.0,
+                });
+                entries.len() - 1
+            }
+        };
+
+        let entry = &mut entries[idx];
+        entry.requests += 1;
+        entry.prompt_tokens += usage.prompt_tokens;
+        entry.completion_tokens += usage.completion_tokens;
+        entry.cost_usd += cost;
+
+        if let Err(err) = write_json(&self.config.path, &*entries) {
+            error!("Failed to save usage to {}: {}", self.config.path, err);
+        }
+    }
+
+    /// Entries matching the query, oldest first
+    pub fn entries(&self, query: &UsageQuery) -> Vec<UsageEntry> {
+        let mut found: Vec<UsageEntry> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| query.matches(e))
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| (a.day, &a.account, &a.model).cmp(&(b.day, &b.account, &b.model)));
+        found
+    }
+
+    pub fn totals(&self, query: &UsageQuery) -> UsageTotals {
+        self.entries(query)
+            .iter()
+            .fold(UsageTotals::default(), |mut t, e| {
+                t.requests += e.requests;
+                t.prompt_tokens += e.prompt_tokens;
+                t.completion_tokens += e.completion_tokens;
+                t.cost_usd += e.cost_usd;
+                t
+            })
+    }
+
+    /// Fails if the account is over its daily or monthly budget.
+    pub fn check_budget(&self, account: &Account) -> Result<(), BudgetError> {
+        let today = Utc::now().date_naive();
+        let spent_since = |from: NaiveDate| {
+            self.totals(&UsageQuery {
+                account: Some(account.account_name.clone()),
+                from: Some(from),
+                ..Default::default()
+            })
+            .cost_usd
+        };
+
+        if let Some(limit) = account.budget.daily_usd {
+            let spent = spent_since(today);
+            if spent >= limit {
+                return Err(BudgetError::Daily {
+                    account: account.account_name.clone(),
+                    spent,
+                    limit,
+                });
+            }
+        }
+
+        if let Some(limit) = account.budget.monthly_usd {
+            let spent = spent_since(today.with_day(1).unwrap_or(today));
+            if spent >= limit {
+                return Err(BudgetError::Monthly {
+                    account: account.account_name.clone(),
+                    spent,
+                    limit,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub static USAGE: Lazy<UsageLedger> = Lazy::new(|| UsageLedger::open(CONFIG.get().usage.clone()));
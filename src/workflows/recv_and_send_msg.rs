@@ -1,12 +1,34 @@
-use log::error;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{debug, error, info, warn};
 
 use crate::{
-    chat_model::{providers::openai::OpenAI, service::completion},
-    config::Account,
-    twitch::Twitch,
+    chat_model::{
+        core::{CachePolicy, ChatModel},
+        middleware::{
+            cache::{Cached, RESPONSE_CACHE},
+            cassette::Cassette,
+            retry::Resilient,
+        },
+        service::completion,
+        tools::builtin,
+    },
+    config::{utils::get_account_names, Account, BlockAction, CONFIG},
+    consent::{self, OPT_OUTS},
+    experiments::{pick_variant, Outcome, EXPERIMENTS},
+    kill_switch, safety, sanctions,
+    store::STORE,
+    twitch::{
+        room_state::{self, SendDecision},
+        sent_log::record_sent,
+        Twitch, TwitchError, UserMsg,
+    },
+    usage::USAGE,
+    viewers,
 };
 
-pub async fn recv_and_send_msg(account: &Account) {
+pub async fn recv_and_send_msg(account: &Account, client: &reqwest::Client) {
     let twitch = Twitch::new(account);
 
     let ws = match twitch.connect_to_chat().await {
@@ -17,7 +39,7 @@ pub async fn recv_and_send_msg(account: &Account) {
         }
     };
 
-    let chats = match twitch.receive_chat(ws, account.chat_history_size).await {
+    let mut chats = match twitch.receive_chat(ws, account.new_messages()).await {
         Ok(chats) => chats,
         Err(err) => {
             error!("{:?}", err);
@@ -25,17 +47,187 @@ pub async fn recv_and_send_msg(account: &Account) {
         }
     };
 
-    let openai = OpenAI::new(account.gpt_model.clone());
+    kill_switch::handle_commands(&chats);
+    chats.retain(|m| !kill_switch::is_command(&m.message));
 
-    let generated_msg = match completion::generate_chat(&chats, account, &openai).await {
-        Ok(message) => message,
-        Err(err) => {
-            error!("{:?}", err);
+    // !botoff/!boton are read even while the bot is off, nothing is posted without consent
+    let toggle = consent::handle_commands(account, &chats);
+    if let Some(toggle) = toggle.filter(|_| account.broadcaster_consent) {
+        match twitch.connect_to_chat().await {
+            Ok(mut ws) => {
+                let ack = consent::disclose(account, toggle.acknowledgement());
+                if let Err(err) = twitch.send_chat(&mut ws, ack).await {
+                    error!("{:?}", err);
+                }
+            }
+            Err(err) => error!("{:?}", err),
+        }
+    }
+    chats.retain(|m| !consent::is_command(&m.message));
+
+    // !forgetme is honored even while the bot is off, the rest of their chat is dropped too
+    let forgotten = viewers::handle_forget(&chats);
+    chats.retain(|m| !viewers::is_command(&m.message) && !viewers::sent_by(m, &forgotten));
+    if !forgotten.is_empty() && consent::blocked_reason(account).is_none() {
+        match twitch.connect_to_chat().await {
+            Ok(mut ws) => {
+                let ack = consent::disclose(account, &viewers::acknowledgement(&forgotten));
+                if let Err(err) = twitch.send_chat(&mut ws, ack).await {
+                    error!("{:?}", err);
+                }
+            }
+            Err(err) => error!("{:?}", err),
+        }
+    }
+
+    // Kept out of prompts until the history checks passed
+    STORE.record_messages(&account.channel, &chats, false);
+    // Commands above are handled whatever the consent and budget
+    if !account.broadcaster_consent {
+        warn!(
+            "Not posting to {}: broadcaster_consent is not set for {}",
+            account.channel, account.account_name
+        );
+        return;
+    }
+    if let Err(err) = USAGE.check_budget(account) {
+        warn!("Generation paused: {}", err);
+        return;
+    }
+    if OPT_OUTS.is_opted_out(&account.channel) {
+        info!("{} turned the bot off, only reading chat", account.channel);
+        return;
+    }
+    // Reading and logging go on while the kill switch is on
+    if let Some(reason) = kill_switch::killed_reason(account) {
+        debug!("Not posting to {}: {}", account.channel, reason);
+        return;
+    }
+    // Timeouts and bans may have been noticed while reading
+    if let Some(reason) = sanctions::suspended_reason(account) {
+        info!("Not posting: {}", reason);
+        return;
+    }
+    // Nothing generated would get through, e.g. emote-only mode
+    if let Some(reason) = room_state::restriction(account) {
+        info!("Not posting to {}: {}", account.channel, reason);
+        return;
+    }
+    // An acknowledgement above used up the message slow mode allows
+    if let SendDecision::Wait(wait) = room_state::check_send(account) {
+        info!(
+            "Slow mode in {}, next message allowed in {}s",
+            account.channel,
+            wait.as_secs_f32().ceil()
+        );
+        return;
+    }
+    // Slow mode allows one message per cycle, a due notice takes this one
+    if room_state::slow_delay(account) > 0 {
+        if let Some(notice) = consent::take_due_notice(account) {
+            match twitch.connect_to_chat().await {
+                Ok(mut ws) => {
+                    if let Err(err) = twitch.send_chat(&mut ws, notice).await {
+                        error!("{:?}", err);
+                    }
+                }
+                Err(err) => error!("{:?}", err),
+            }
             return;
         }
+    }
+
+    let resilient = Resilient::from_account(account, client);
+    let mut model: Box<dyn ChatModel + Send + Sync> = match &account.cassette {
+        Some(cassette) => Box::new(Cassette::new(resilient, cassette.clone())),
+        None => Box::new(resilient),
     };
+    if CONFIG.get().cache.enabled {
+        model = Box::new(Cached::new(model, RESPONSE_CACHE.clone()));
+    }
+    // This cycle's chat and any an earlier cycle left unchecked when it ended early
+    let since = Utc::now() - chrono::Duration::seconds(account.history_window_secs as i64);
+    let pending = STORE.pending_messages(&account.channel, &account.account_name, Some(since));
+    let chats = safety::filter_history(
+        account,
+        client,
+        pending.iter().map(|(_, m)| m.clone()).collect(),
+    )
+    .await;
+    let checked: Vec<(i64, bool)> = pending
+        .iter()
+        .map(|(id, m)| (*id, chats.contains(m)))
+        .collect();
+    STORE.mark_checked(&account.account_name, &checked);
+    viewers::see(&chats);
+    let tools = builtin::registry(account, &chats, client);
 
-    let ws = match twitch.connect_to_chat().await {
+    // Stored chat reaches back past this connection, other bot accounts stay hidden
+    let hidden: Vec<String> = get_account_names()
+        .into_iter()
+        .filter(|name| *name != account.account_name)
+        .collect();
+    let stored = STORE.recent_messages(
+        &account.channel,
+        &account.account_name,
+        account.chat_history_size,
+        Some(since),
+        &hidden,
+    );
+    let history: Vec<UserMsg> = stored.iter().map(|(_, m)| m.clone()).collect();
+
+    let variant = pick_variant(account);
+    let instruction = variant.map_or(account.instruction.as_str(), |v| v.instruction.as_str());
+
+    let flagged =
+        completion::classify_history(&history, account, instruction, &model, client).await;
+
+    // A blocked message is regenerated without the cache, which would return it again
+    let mut cache = CachePolicy::Use;
+    let mut regenerations = 0;
+    let generated_msg = loop {
+        let message = match completion::generate_chat(
+            &stored,
+            &flagged,
+            account,
+            instruction,
+            &model,
+            &tools,
+            cache,
+        )
+        .await
+        {
+            Ok(message) => message,
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        };
+
+        match safety::check_output(account, client, &message).await {
+            Ok(()) => break message,
+            Err(reason) => {
+                warn!(
+                    "Blocked message for {}: {} ({:?})",
+                    account.channel, reason, message
+                );
+                STORE.set_outcome(account, &message, &format!("blocked: {}", reason));
+                if account.safety.on_block == BlockAction::DROP
+                    || regenerations >= account.safety.retries
+                {
+                    return;
+                }
+                regenerations += 1;
+                cache = CachePolicy::Bypass;
+            }
+        }
+    };
+    let trial = variant.map(|v| {
+        info!("{} generated with variant {}", account.account_name, v.name);
+        EXPERIMENTS.start(account, &v.name, &generated_msg)
+    });
+
+    let mut ws = match twitch.connect_to_chat().await {
         Ok(ws) => ws,
         Err(err) => {
             error!("{:?}", err);
@@ -43,8 +235,80 @@ pub async fn recv_and_send_msg(account: &Account) {
         }
     };
 
-    if let Err(err) = twitch.send_chat(ws, generated_msg).await {
-        error!("{:?}", err);
+    // The channel may have opted out or the bot been timed out while generating
+    if let Some(reason) =
+        consent::blocked_reason(account).or_else(|| sanctions::suspended_reason(account))
+    {
+        info!("Not sending: {}", reason);
+        STORE.set_outcome(account, &generated_msg, &format!("not sent: {}", reason));
         return;
     }
+
+    let outgoing = consent::disclose(account, &generated_msg);
+    if let Err(err) = twitch.send_chat(&mut ws, outgoing.clone()).await {
+        match &err {
+            // the mode, kill switch or a sanction changed while generating
+            TwitchError::Restricted(reason)
+            | TwitchError::Killed(reason)
+            | TwitchError::Suspended(reason) => {
+                info!("Not posting to {}: {}", account.channel, reason)
+            }
+            _ => error!("{:?}", err),
+        }
+        STORE.set_outcome(account, &generated_msg, &format!("failed: {}", err));
+        if let Some(id) = trial {
+            EXPERIMENTS.finish(
+                id,
+                Outcome {
+                    rejected: Some(err.to_string()),
+                    observed: true,
+                    ..Default::default()
+                },
+            );
+        }
+        return;
+    }
+    record_sent(account, &generated_msg);
+    STORE.set_outcome(account, &generated_msg, "sent");
+    STORE.record_messages(
+        &account.channel,
+        &[UserMsg {
+            sender: account.account_name.clone(),
+            message: generated_msg.clone(),
+            ..Default::default()
+        }],
+        true,
+    );
+
+    if room_state::slow_delay(account) == 0 {
+        if let Some(notice) = consent::take_due_notice(account) {
+            if let Err(err) = twitch.send_chat(&mut ws, notice).await {
+                error!("{:?}", err);
+            }
+        }
+    }
+
+    viewers::summarize_due(&model, account, &history).await;
+
+    // Watch the reaction in the background, the cycle is done
+    if let Some(id) = trial {
+        let account = account.clone();
+        let window = Duration::from_secs(CONFIG.get().experiments.observe_seconds);
+        tokio::spawn(async move {
+            let twitch = Twitch::new(&account);
+            match twitch.observe(ws, &outgoing, window).await {
+                Ok(reaction) => EXPERIMENTS.finish(id, reaction.into()),
+                Err(err) => {
+                    warn!("Stopped watching chat of {}: {:?}", account.channel, err);
+                    EXPERIMENTS.finish(
+                        id,
+                        Outcome {
+                            sent: true,
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        });
+    }
 }